@@ -1,27 +1,33 @@
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::net::SocketAddr;
 use anyhow::Context;
 use serde_json::Value;
 
-use crate::{send_raw, GelfMessageWrapper, GelfPacket};
+use crate::{archive::ArchiveSender, configuration::BackendProtocol, dead_letter::DeadLetterSender, gelf::PayloadError, mirror::MirrorSender, pool::BackendServer, send_raw, stream::StreamConnections, webhook::WebhookSender, GelfMessage, GelfMessageWrapper, GelfPacket};
 
-
-// for chunked messages we always should select the same backend for each chunk
-fn select_backend(backends: &[SocketAddr], value: u64) -> &SocketAddr {
-    // Calculate the segment size based on the number of backends
-    let segment_size = u8::MAX as usize / backends.len();
-    // Calculate the index of the backend based on the value
-    let index = (value as usize) / segment_size;
-    // Clamp the index to the range of the backends vector to avoid going out of bounds
-    let clamped_index = index.min(backends.len() - 1);
-    &backends[clamped_index]
+// everything besides the primary backends that the balancer hands messages over to
+#[derive(Default)]
+pub struct Outputs {
+    pub mirror: Option<MirrorSender>,
+    pub dead_letters: Option<DeadLetterSender>,
+    pub webhooks: Option<WebhookSender>,
+    pub archive: Option<ArchiveSender>
 }
 
+impl Outputs {
+    // called with every parsed message once it has been modified, right before it is encoded again
+    fn inspect(&mut self, msg: &GelfMessage, source: SocketAddr) {
+        if let Some(webhooks) = self.webhooks.as_mut() {
+            webhooks.inspect(msg,source);
+        }
+        if let Some(archive) = self.archive.as_mut() {
+            archive.inspect(msg);
+        }
+    }
+}
 
-pub fn balancer(state: std::sync::Arc<crate::State>,config:std::sync::Arc<crate::Configuration>,receiver: std::sync::mpsc::Receiver<GelfMessageWrapper>,backends: Vec<SocketAddr>) {
+pub fn balancer(state: std::sync::Arc<crate::State>,config:std::sync::Arc<crate::Configuration>,receiver: std::sync::mpsc::Receiver<GelfMessageWrapper>,mut outputs: Outputs) {
     
-    let mut backend_cycle = backends.iter().cycle();
-    let normal_sender_socket_v4 : UdpSocket = UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(std::net::Ipv4Addr::UNSPECIFIED,0))).unwrap();
-    let normal_sender_socket_v6 : UdpSocket = UdpSocket::bind(SocketAddr::V6(SocketAddrV6::new(std::net::Ipv6Addr::UNSPECIFIED,0, 0, 0))).unwrap();
+    let mut forwarder = Forwarder::new();
     
     loop {
 
@@ -29,89 +35,86 @@ pub fn balancer(state: std::sync::Arc<crate::State>,config:std::sync::Arc<crate:
 
         // if we do not need to do any modification to messages in flight, we can just pass on any packet without temp storage
         if state.otf_massage_required {
-            if let GelfMessageWrapper::Chunked(mut chunked_pkg) = packet {
-                let mut guard = state.chunked_messages.lock().unwrap();
-                let existing_info = guard.get_mut(&chunked_pkg.id);
-                
-                if let Some(old) = existing_info {  
-                    old.chunks.push(chunked_pkg.chunks.remove(0));
-                    if old.is_complete() == false {
-                        // we added the chunk to our existing info about this message, but we are still waiting for more chunks
+            if let GelfMessageWrapper::Chunked(chunked_pkg) = packet {
+                let (id,source) = (chunked_pkg.id,chunked_pkg.chunks[0].source_ip);
+                match state.chunked_messages.add(chunked_pkg) {
+                    // here we now know that we have all chunks that we expected to see for this message..
+                    Ok(Some(completed_chunked_pkg)) => packet = GelfMessageWrapper::Chunked(completed_chunked_pkg),
+                    // we added the chunk to our existing info about this message, but we are still waiting for more chunks
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log::debug!("dropping a chunk of message {id} from {source}: {e}");
+                        state.count_dropped_chunk(e.reason());
                         continue
                     }
-                } else {
-                    // this is the first chunk we see for this message, so we can continue after adding it to the state.
-                    guard.insert(chunked_pkg.id, chunked_pkg);
-                    continue
                 }
-                // here we now know that we have all chunks that we expected to see for this message..
-                // lets remove it from the state and pass it on to the next step
-                let (_,completed_chunked_pkg) = guard.remove_entry(&chunked_pkg.id).expect("failed to remove chunk entry prior to step 2. this is a bug in gelflb.");
-                packet = GelfMessageWrapper::Chunked(completed_chunked_pkg)
             } 
         }
 
-        let selected_backend_socket = if packet.is_chunked() {
-            if let Some(pkg_id) = packet.pkg_id() {
-                Some(select_backend(&backends,pkg_id))
-            } else {
-                log::warn!("We received a chunked message with no id. this should not be possible..");
-                None
-            }         
-        } else {
-            Some(backend_cycle.next().unwrap())
+        let message = match massage(&state,&config,&packet) {
+            Ok(x) => x,
+            Err(e) => {
+                drop_failed_message(&state,&outputs,&packet,e);
+                continue
+            }
         };
 
-        if let Some(backend) = selected_backend_socket {
-            match massage(&state,&config,&mut packet) {
-                Ok(()) => {
-                    match forward(&config,&packet, backend,&normal_sender_socket_v4,&normal_sender_socket_v6) {
-                        Ok(()) =>
-
-                            match packet {
-                                GelfMessageWrapper::Chunked(msg) => {
-                                    let chunk_count = msg.chunks.len();
-                                    // if this is a complete gathering of packets in a chunk we count it as a single message
-                                    if chunk_count > 1 {
-                                        state.nr_of_forwarded_messages.write().and_then(|mut x|Ok(*x=*x+1))
-                                            .expect("should always be possible to increment fwd count");
-                                    }
-                                    // if this is forwarded as-is without temp storage, we will only have a single incomplete chunk here,
-                                    // and so we will only log this as a message for a single one of the packets/chunks of this message 
-                                    else if chunk_count == 1 {
-                                        if msg.chunks[0].sequence_number == 0 {
-                                            state.nr_of_forwarded_messages.write().and_then(|mut x|Ok(*x=*x+1))
-                                            .expect("should always be possible to increment fwd count");
-                                        }
-                                    }
-                                    // this is just not supposed to be possible  
-                                    else {
-                                        panic!("there is a bug in gelflb: forwarding of a chunked packed failed due to it having 0 or less packets: {:?}",msg)
-                                    }
-                                },
-                                GelfMessageWrapper::Simple(_) => {
-                                    state.nr_of_forwarded_messages.write().and_then(|mut x|Ok(*x=*x+1))
-                                        .expect("should always be possible to increment fwd count");
-                                },
-                            }
-                            
-                        Err(e) => 
-                            log::error!("failed to forward a message - at least one packet was not sent! {e}.")
-                    }
-                },
-                Err(msg) => eprintln!("packet massage failure: {msg}")
+        let pool = state.router.route(&packet,message.as_ref());
+        let Some(mut backend) = pool.select(&packet,message.as_ref()) else {
+            log::warn!("dropping a message as there is no backend available for it");
+            state.count_dropped("no backend available");
+            continue
+        };
+
+        if let Some(message) = message {
+            outputs.inspect(&message,packet.pkg_src());
+            if let Err(e) = packet.set_payload(message,&config) {
+                drop_failed_message(&state,&outputs,&packet,e.into());
+                continue
             }
         }
+
+        if let Some(mirror) = outputs.mirror.as_mut() {
+            mirror.offer(&packet);
+        }
+        if matches!(packet,GelfMessageWrapper::Stream(_)) && !backend.protocol.is_stream() {
+            match pool.select_stream() {
+                Some(x) => backend = x,
+                None => {
+                    log::warn!("dropping an oversized message as there are no tcp or http backends configured");
+                    state.count_dropped("no stream backend");
+                    continue
+                }
+            }
+        }
+        match forwarder.forward(&config,&packet,&backend) {
+            Ok(()) => if is_start_of_message(&packet) {
+                state.nr_of_forwarded_messages.write().map(|mut x| *x += 1)
+                    .expect("should always be possible to increment fwd count");
+                backend.count_forwarded();
+            },
+            Err(e) => 
+                log::error!("failed to forward a message - at least one packet was not sent! {e:#}.")
+        }
     }
 
 }
 
+fn drop_failed_message(state: &crate::State,outputs: &Outputs,packet: &GelfMessageWrapper,e: anyhow::Error) {
+    let reason = e.downcast_ref::<PayloadError>().map(|x|x.reason()).unwrap_or("processing failure");
+    log::debug!("dropping a message from {}: {e:#}",packet.pkg_src());
+    state.count_dropped(reason);
+    if let Some(dead_letters) = &outputs.dead_letters {
+        dead_letters.send(packet,reason,&e);
+    }
+}
 
-fn massage(state: &crate::State,config:&crate::Configuration,packet: &mut GelfMessageWrapper) -> anyhow::Result<()> {
+// parses the message and applies the configured modifications. returns None when we only pass packets through as they are.
+fn massage(state: &crate::State,config:&crate::Configuration,packet: &GelfMessageWrapper) -> anyhow::Result<Option<GelfMessage>> {
     
-    if state.otf_massage_required == false { 
+    if !state.otf_massage_required { 
         log::trace!("massaging is disabled, sub-routine bypassed");
-        return Ok (())
+        return Ok(None)
      }
     
     log::trace!("massaging a packet");
@@ -119,14 +122,12 @@ fn massage(state: &crate::State,config:&crate::Configuration,packet: &mut GelfMe
     let mut j = packet.get_payload()?;
    
     let src_key = "_gelflb_original_source_addr";
-    if config.attach_source_info {
-        if !j.additional_fields.contains_key(src_key) {
-            log::trace!("attaching {src_key} field to a message.");
-            j.additional_fields.extend(vec![(src_key.into(), Value::from(packet.pkg_src().ip().to_string()))]);
-        }
+    if config.attach_source_info && !j.additional_fields.contains_key(src_key) {
+        log::trace!("attaching {src_key} field to a message.");
+        j.additional_fields.extend(vec![(src_key.into(), Value::from(packet.pkg_src().ip().to_string()))]);
     }
 
-    if config.strip_fields.len() > 0 {
+    if !config.strip_fields.is_empty() {
         log::trace!("making sure to strip these fields from a message: {:?}",config.strip_fields);
         j.additional_fields.retain(|x,_|!config.strip_fields.contains(&format!("_{x}")));
     }
@@ -140,49 +141,110 @@ fn massage(state: &crate::State,config:&crate::Configuration,packet: &mut GelfMe
         }
     }
 
-    packet.set_payload(j,&config);
-
-    Ok(())
+    Ok(Some(j))
 
 }
 
-fn forward(config:&crate::Configuration,packet: &GelfMessageWrapper, selected_backend_socket: &SocketAddr, normal_socket_v4: &UdpSocket,normal_socket_v6: &UdpSocket) -> anyhow::Result<()> {
-   
-    let src = packet.pkg_src();
-
-    let mut packets : Vec<&GelfPacket> = vec![];
+// returns true if forwarding this packet means that we have forwarded one more message
+pub fn is_start_of_message(packet: &GelfMessageWrapper) -> bool {
     match packet {
-        GelfMessageWrapper::Chunked(c) => {
-            for p in &c.chunks { 
-                packets.push(p)
+        GelfMessageWrapper::Chunked(msg) => {
+            let chunk_count = msg.chunks.len();
+            // if this is a complete gathering of packets in a chunk we count it as a single message
+            if chunk_count > 1 {
+                true
+            }
+            // if this is forwarded as-is without temp storage, we will only have a single incomplete chunk here,
+            // and so we will only log this as a message for a single one of the packets/chunks of this message 
+            else if chunk_count == 1 {
+                msg.chunks[0].sequence_number == 0
+            }
+            // this is just not supposed to be possible  
+            else {
+                panic!("there is a bug in gelflb: forwarding of a chunked packed failed due to it having 0 or less packets: {:?}",msg)
             }
         },
-        GelfMessageWrapper::Simple(p) => packets.push(p),
-    };
-
-    for pkg in packets {
-        
-        if config.transparent {
-            let data =  crate::package_builder::build_custom_packet(
-                src.clone(), 
-                *selected_backend_socket, 
-                &pkg.data
-            );
-            log::trace!("forwarding a packet via raw socket");
-            send_raw(&data,*selected_backend_socket).context("failed to send raw")?;
+        GelfMessageWrapper::Simple(_) | GelfMessageWrapper::Stream(_) => true
+    }
+}
+
+// owns the sockets and connections used for sending, each thread that forwards messages has its own
+pub struct Forwarder {
+    stream_connections: StreamConnections,
+    http_agent: ureq::Agent
+}
+
+impl Forwarder {
+
+    pub fn new() -> Self {
+        Self {
+            stream_connections: StreamConnections::default(),
+            http_agent: ureq::Agent::new()
+        }
+    }
+
+    // the outcome is fed to the circuit breaker of the backend. messages for tcp and http backends are only queued here,
+    // their sending thread takes care of that once it has sent them.
+    pub fn forward(&mut self,config:&crate::Configuration,packet: &GelfMessageWrapper, selected_backend: &BackendServer) -> anyhow::Result<()> {
+        selected_backend.start_probe();
+        let result = if selected_backend.has_queue() {
+            match selected_backend.enqueue(packet.to_stream_frame()?) {
+                Ok(()) => return Ok(()),
+                Err(e) => Err(e)
+            }
+        } else if selected_backend.protocol.is_stream() {
+            // backends outside of pools (dead letters) have no sending thread
+            packet.to_stream_frame().and_then(|frame| self.send_stream(selected_backend, &frame))
+        } else {
+            self.send_udp(config, packet, selected_backend)
+        };
+        selected_backend.record_send(&result);
+        result
+    }
+
+    // tcp can not be spoofed like we do with raw udp packets, so stream backends always see us as the source
+    fn send_stream(&mut self, selected_backend: &BackendServer, frame: &[u8]) -> anyhow::Result<()> {
+        if selected_backend.protocol == BackendProtocol::Http {
+            log::trace!("forwarding a message via http");
+            crate::stream::post(&self.http_agent, selected_backend.addr, frame)
         } else {
-            if selected_backend_socket.is_ipv4() {
-                log::trace!("forwarding via basic ipv4 udp socket");
-                normal_socket_v4.send_to(&pkg.data, *selected_backend_socket).context("failed to send")?;
+            log::trace!("forwarding a message via tcp");
+            self.stream_connections.send(selected_backend.addr, frame)
+        }
+    }
+
+    fn send_udp(&mut self,config:&crate::Configuration,packet: &GelfMessageWrapper, selected_backend: &BackendServer) -> anyhow::Result<()> {
+       
+        let src = packet.pkg_src();
+        let selected_backend_socket = &selected_backend.addr;
+
+        let mut packets : Vec<&GelfPacket> = vec![];
+        match packet {
+            GelfMessageWrapper::Chunked(c) => {
+                for p in &c.chunks { 
+                    packets.push(p)
+                }
+            },
+            GelfMessageWrapper::Simple(p) => packets.push(p),
+            GelfMessageWrapper::Stream(_) => anyhow::bail!("a stream frame can not be sent to the udp backend {selected_backend_socket}"),
+        };
+
+        for pkg in packets {
+            
+            if config.transparent {
+                let data =  crate::package_builder::build_custom_packet(
+                    src, 
+                    *selected_backend_socket, 
+                    &pkg.data
+                );
+                log::trace!("forwarding a packet via raw socket");
+                send_raw(&data,*selected_backend_socket).context("failed to send raw")?;
             } else {
-                log::trace!("forwarding via basic ipv6 udp socket");
-                normal_socket_v6.send_to(&pkg.data, *selected_backend_socket).context("failed to send")?;
+                log::trace!("forwarding via basic udp socket");
+                selected_backend.udp_socket()?.send(&pkg.data).context(format!("failed to send to {selected_backend_socket}"))?;
             }
-            
         }
-   }
 
-   Ok(())
-
-    
+        Ok(())
+    }
 }
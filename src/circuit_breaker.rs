@@ -0,0 +1,208 @@
+use std::time::{Duration, Instant};
+
+use crate::configuration::CircuitBreaker;
+
+// a half-open backend gets at most one probe message per interval
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CircuitState {
+    /// normal operation, counting the failures in the current window
+    Closed { failures: u32, window_start: Instant },
+    /// no traffic until open_seconds have passed
+    Open { since: Instant },
+    /// letting single probe messages through, closes again after enough of them succeeded
+    HalfOpen { successes: u32, last_probe: Instant }
+}
+
+// passive failure detection for one backend, fed with the result of every send to it
+#[derive(Debug, Clone)]
+pub struct Circuit {
+    config: Option<CircuitBreaker>,
+    state: CircuitState
+}
+
+impl Circuit {
+
+    pub fn new(config: Option<CircuitBreaker>) -> Self {
+        Self { config, state: closed() }
+    }
+
+    /// Only looks at the state, the balancer may ask several times while picking one backend.
+    pub fn allows_traffic(&self) -> bool {
+        let Some(config) = &self.config else { return true };
+        match self.state {
+            CircuitState::Closed { .. } => true,
+            CircuitState::Open { since } => since.elapsed() >= Duration::from_secs(config.open_seconds),
+            CircuitState::HalfOpen { last_probe, .. } => last_probe.elapsed() >= PROBE_INTERVAL
+        }
+    }
+
+    /// Called with every message that is sent to (or queued for) the backend. a message that gets through while the circuit
+    /// is not closed is a probe, it uses up the turn so that the next probe has to wait for the next interval.
+    pub fn start_probe(&mut self) {
+        if !self.allows_traffic() {
+            return
+        }
+        match self.state {
+            CircuitState::Closed { .. } => {},
+            CircuitState::Open { .. } => self.state = CircuitState::HalfOpen { successes: 0, last_probe: Instant::now() },
+            CircuitState::HalfOpen { successes, .. } => self.state = CircuitState::HalfOpen { successes, last_probe: Instant::now() }
+        }
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            CircuitState::Closed { .. } => "closed",
+            CircuitState::Open { .. } => "open",
+            CircuitState::HalfOpen { .. } => "half-open"
+        }
+    }
+
+    /// Returns true if this success closed the circuit again.
+    pub fn record_success(&mut self) -> bool {
+        let Some(config) = &self.config else { return false };
+        // successes do not reset the failure count: an icmp error only shows up on the send after the one that caused it,
+        // so a backend without a listener alternates between successful and failed sends.
+        let successes = match self.state {
+            CircuitState::Closed { .. } => return false,
+            // a send while open can only be the first probe
+            CircuitState::Open { .. } => 0,
+            CircuitState::HalfOpen { successes, .. } => successes
+        };
+        if successes + 1 >= config.half_open_successes {
+            self.state = closed();
+            true
+        } else {
+            self.state = CircuitState::HalfOpen { successes: successes + 1, last_probe: Instant::now() };
+            false
+        }
+    }
+
+    /// Returns true if this failure opened the circuit.
+    pub fn record_failure(&mut self) -> bool {
+        let Some(config) = &self.config else { return false };
+        let failures = match self.state {
+            CircuitState::Closed { window_start, .. } if window_start.elapsed() >= Duration::from_secs(config.failure_window_seconds) => 1,
+            CircuitState::Closed { failures, .. } => failures + 1,
+            // a failed probe starts the wait all over again
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => {
+                self.state = CircuitState::Open { since: Instant::now() };
+                return false
+            }
+        };
+        if failures >= config.failure_threshold {
+            self.state = CircuitState::Open { since: Instant::now() };
+            true
+        } else {
+            if failures == 1 {
+                self.state = CircuitState::Closed { failures, window_start: Instant::now() };
+            } else if let CircuitState::Closed { window_start, .. } = self.state {
+                self.state = CircuitState::Closed { failures, window_start };
+            }
+            false
+        }
+    }
+}
+
+fn closed() -> CircuitState {
+    CircuitState::Closed { failures: 0, window_start: Instant::now() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn circuit() -> Circuit {
+        Circuit::new(Some(CircuitBreaker { failure_threshold: 3, failure_window_seconds: 10, open_seconds: 30, half_open_successes: 2 }))
+    }
+
+    // moves the circuit back in time instead of waiting
+    fn age(circuit: &mut Circuit, by: Duration) {
+        circuit.state = match circuit.state {
+            CircuitState::Closed { failures, window_start } => CircuitState::Closed { failures, window_start: window_start - by },
+            CircuitState::Open { since } => CircuitState::Open { since: since - by },
+            CircuitState::HalfOpen { successes, last_probe } => CircuitState::HalfOpen { successes, last_probe: last_probe - by }
+        };
+    }
+
+    // what the balancer does with a backend that it picked
+    fn probe(circuit: &mut Circuit, success: bool) {
+        circuit.start_probe();
+        if success { circuit.record_success(); } else { circuit.record_failure(); }
+    }
+
+    #[test]
+    fn opens_after_failures_and_closes_after_successful_probes() {
+        let mut circuit = circuit();
+        assert!(!circuit.record_failure());
+        assert!(!circuit.record_failure());
+        assert!(circuit.record_failure());
+        assert_eq!(circuit.state_name(), "open");
+        assert!(!circuit.allows_traffic());
+
+        age(&mut circuit, Duration::from_secs(30));
+        assert!(circuit.allows_traffic());
+        probe(&mut circuit, true);
+        assert_eq!(circuit.state_name(), "half-open");
+        age(&mut circuit, PROBE_INTERVAL);
+        circuit.start_probe();
+        assert!(circuit.record_success());
+        assert_eq!(circuit.state_name(), "closed");
+        assert!(circuit.allows_traffic());
+    }
+
+    #[test]
+    fn failures_outside_of_the_window_do_not_open() {
+        let mut circuit = circuit();
+        circuit.record_failure();
+        circuit.record_failure();
+        age(&mut circuit, Duration::from_secs(10));
+        assert!(!circuit.record_failure());
+        assert_eq!(circuit.state_name(), "closed");
+    }
+
+    #[test]
+    fn a_failed_probe_opens_again() {
+        let mut circuit = circuit();
+        for _ in 0..3 {
+            circuit.record_failure();
+        }
+        age(&mut circuit, Duration::from_secs(30));
+        probe(&mut circuit, true);
+        age(&mut circuit, PROBE_INTERVAL);
+        probe(&mut circuit, false);
+        assert_eq!(circuit.state_name(), "open");
+        assert!(!circuit.allows_traffic());
+        age(&mut circuit, Duration::from_secs(29));
+        assert!(!circuit.allows_traffic(), "the wait starts over after a failed probe");
+    }
+
+    #[test]
+    fn only_one_probe_per_interval() {
+        let mut circuit = circuit();
+        for _ in 0..3 {
+            circuit.record_failure();
+        }
+        age(&mut circuit, Duration::from_secs(30));
+        // the probe is still on its way (or queued), nothing else gets through until the next interval
+        circuit.start_probe();
+        assert_eq!(circuit.state_name(), "half-open");
+        assert!(!circuit.allows_traffic());
+        circuit.record_success();
+        assert!(!circuit.allows_traffic());
+        age(&mut circuit, PROBE_INTERVAL);
+        assert!(circuit.allows_traffic());
+    }
+
+    #[test]
+    fn without_configuration_traffic_always_flows() {
+        let mut circuit = Circuit::new(None);
+        for _ in 0..10 {
+            assert!(!circuit.record_failure());
+        }
+        circuit.start_probe();
+        assert!(circuit.allows_traffic());
+        assert_eq!(circuit.state_name(), "closed");
+    }
+}
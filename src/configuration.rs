@@ -1,4 +1,6 @@
+use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
+use crate::rules::{Cidr, Pattern};
 
 #[derive(Debug,serde::Deserialize,serde::Serialize)]
 pub struct Configuration {
@@ -6,6 +8,12 @@ pub struct Configuration {
     pub listen_ip : String,
     pub listen_port: u16,
     pub web_ui_port : Option<u16>,
+    /// the address the web ui listens on, all of them by default
+    #[serde(default)]
+    pub web_ui_ip : Option<String>,
+    /// requests that change something (draining backends, split percentages) need this as bearer token
+    #[serde(default, skip_serializing)]
+    pub web_api_token : Option<String>,
     #[serde(default)]
     pub strip_fields: Vec<String>,
     #[serde(default)]
@@ -20,28 +28,405 @@ pub struct Configuration {
     pub allowed_source_ips: Vec<String>,
     #[serde(default)]
     pub backends: Vec<Backend>,
+    /// a json file with more backends (same fields as above), watched for changes
+    #[serde(default)]
+    pub backends_file: Option<String>,
+    /// more backends, found by asking graylog which nodes its cluster has
+    #[serde(default)]
+    pub graylog_discovery: Option<GraylogDiscovery>,
     #[serde(default = "default_use_gzip")]
     pub use_gzip : Option<bool>,
     #[serde(default = "default_chunk_size")]
-    pub chunk_size : u64
+    pub chunk_size : u64,
+    #[serde(default)]
+    pub oversized_message_policy : OversizedMessagePolicy,
+    #[serde(default)]
+    pub chunk_affinity_by_source : bool,
+    /// how long we wait for all chunks of a message before it is dropped, only when chunks are collected
+    #[serde(default = "default_reassembly_timeout_seconds")]
+    pub reassembly_timeout_seconds : u64,
+    /// how often messages that ran out of time are dropped
+    #[serde(default = "default_reassembly_sweep_seconds")]
+    pub reassembly_sweep_seconds : u64,
+    #[serde(default)]
+    pub balancing : Balancing,
+    /// used for all backends that do not have a health check of their own
+    #[serde(default)]
+    pub health_check : Option<HealthCheck>,
+    #[serde(default)]
+    pub circuit_breaker : Option<CircuitBreaker>,
+    /// named pools that routes can send messages to, next to the pool formed by backends
+    #[serde(default)]
+    pub pools : Vec<NamedPool>,
+    /// how often backend names are resolved again, 0 only resolves them at startup
+    #[serde(default = "default_dns_refresh_seconds")]
+    pub dns_refresh_seconds : u64,
+    /// the server for srv lookups ("ip" or "ip:port"), defaults to the first nameserver in /etc/resolv.conf
+    #[serde(default)]
+    pub dns_server : Option<String>,
+    /// the pool for everything from some networks, checked before the routes and without looking at the messages
+    #[serde(default)]
+    pub networks : Vec<Network>,
+    /// checked in order, the first matching route picks the pool. messages that match no route go to the default pool
+    #[serde(default)]
+    pub routes : Vec<Route>,
+    /// the pool (or split) for messages that match no route
+    #[serde(default = "default_pool_name")]
+    pub default_pool : String,
+    /// shares of the traffic for several pools, routes and default_pool can refer to a split like to a pool
+    #[serde(default)]
+    pub splits : Vec<Split>,
+    #[serde(default)]
+    pub mirror : Option<Mirror>,
+    #[serde(default)]
+    pub dead_letter : Option<DeadLetter>,
+    #[serde(default)]
+    pub webhooks : Vec<Webhook>,
+    #[serde(default)]
+    pub archive : Option<Archive>
 }
 
 fn default_ip() -> String { "127.0.0.1".to_string() }
 fn default_log_level() -> String { std::env::var("RUST_LOG").unwrap_or("info".into()) }
 const fn default_transparent() -> bool { true }
+const fn default_reassembly_timeout_seconds() -> u64 { 10 }
+const fn default_reassembly_sweep_seconds() -> u64 { 10 }
 const fn default_chunk_size() -> u64 { 1024 }
 const fn default_use_gzip() -> Option<bool> { Some(true) }
+const fn default_weight() -> u32 { 1 }
+fn default_hash_key() -> String { "host".to_string() }
+const fn default_virtual_nodes() -> u32 { 160 }
+const fn default_min_available_backends() -> usize { 1 }
+fn default_health_check_path() -> String { "/api/system/lbstatus".to_string() }
+const fn default_health_check_interval_seconds() -> u64 { 5 }
+const fn default_health_check_timeout_seconds() -> u64 { 2 }
+const fn default_health_check_rise() -> u32 { 2 }
+const fn default_health_check_fall() -> u32 { 3 }
+const fn default_circuit_breaker_failure_threshold() -> u32 { 5 }
+const fn default_circuit_breaker_failure_window_seconds() -> u64 { 10 }
+const fn default_circuit_breaker_open_seconds() -> u64 { 30 }
+const fn default_circuit_breaker_half_open_successes() -> u32 { 3 }
+const fn default_dns_refresh_seconds() -> u64 { 30 }
+const fn default_graylog_discovery_interval_seconds() -> u64 { 30 }
+fn default_pool_name() -> String { "default".to_string() }
+const fn default_mirror_percentage() -> u8 { 100 }
+const fn default_mirror_queue_size() -> usize { 10_000 }
+const fn default_dead_letter_queue_size() -> usize { 1_000 }
+const fn default_webhook_max_per_minute() -> u32 { 10 }
+fn default_archive_region() -> String { "us-east-1".to_string() }
+const fn default_archive_flush_size() -> u64 { 5 * 1024 * 1024 }
+const fn default_archive_flush_interval_seconds() -> u64 { 300 }
+const fn default_archive_path_style() -> bool { true }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// What to do with a modified message that would need more chunks than GELF allows (128).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedMessagePolicy {
+    /// shorten the full_message field until the message fits
+    #[default]
+    Truncate,
+    /// send the message uncompressed to one of the tcp or http backends instead
+    Stream,
+    /// drop the message and count it
+    Drop
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendProtocol {
+    #[default]
+    Udp,
+    Tcp,
+    /// graylog's gelf http input, every message is posted to /gelf on its own
+    Http
+}
+
+impl BackendProtocol {
+    /// True for the protocols that take complete, uncompressed messages of any size.
+    pub fn is_stream(&self) -> bool {
+        matches!(self, BackendProtocol::Tcp | BackendProtocol::Http)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalancingStrategy {
+    /// weighted round-robin, chunks of a message stick to one backend
+    #[default]
+    RoundRobin,
+    /// a hash ring keyed on hash_key, so that all messages with the same key go to the same backend
+    ConsistentHash,
+    /// every sender sticks to one backend, picked from its ip without looking at the messages
+    SourceIp,
+    /// the backend with the fewest queued messages for its weight, meant for tcp and http backends
+    LeastOutstanding,
+    /// the less loaded of two backends picked at random, by queued messages and recent latency
+    PowerOfTwoChoices
+}
+
+/// How a pool spreads messages over its backends.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Balancing {
+    #[serde(default)]
+    pub strategy: BalancingStrategy,
+    /// "source_ip" or the name of a message field (with leading underscore for additional fields)
+    #[serde(default = "default_hash_key")]
+    pub hash_key: String,
+    /// points on the hash ring per unit of backend weight
+    #[serde(default = "default_virtual_nodes")]
+    pub virtual_nodes: u32,
+    /// a priority group only gets traffic while it has at least this many healthy backends, otherwise the next one takes over
+    #[serde(default = "default_min_available_backends")]
+    pub min_available_backends: usize,
+    /// a backend that comes back (recovers, is added or stops draining) ramps up to its full weight over this many seconds, 0 disables this
+    #[serde(default)]
+    pub slow_start_seconds: u64,
+}
+
+impl Default for Balancing {
+    fn default() -> Self {
+        Balancing {
+            strategy: BalancingStrategy::default(),
+            hash_key: default_hash_key(),
+            virtual_nodes: default_virtual_nodes(),
+            min_available_backends: default_min_available_backends(),
+            slow_start_seconds: 0
+        }
+    }
+}
+
+impl Balancing {
+    /// True if the strategy has to look at the contents of messages to pick a backend.
+    pub fn needs_message(&self) -> bool {
+        self.strategy == BalancingStrategy::ConsistentHash && self.hash_key != "source_ip"
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Backend {
+    /// an ip address or a name, leave empty when using srv
+    #[serde(default)]
     pub ip: String,
+    #[serde(default)]
     pub port: u16,
+    /// discover the backends from the records of this srv name instead, the records give the port, priority and weight
+    pub srv: Option<String>,
+    #[serde(default)]
+    pub protocol: BackendProtocol,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// lower numbers are preferred, backends with a higher number are standby
+    #[serde(default)]
+    pub priority: u32,
+    pub health_check: Option<HealthCheck>,
+    /// a draining backend gets no new messages, chunks of messages it already got still go to it
+    #[serde(default)]
+    pub drain: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckKind {
+    /// the backend is healthy if we can open a tcp connection to it
+    #[default]
+    Tcp,
+    /// the backend is healthy if a GET on path answers with ALIVE, as graylog's load balancer status does
+    Http
+}
+
+/// Periodically checks a backend, unhealthy backends get no traffic until they recover.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HealthCheck {
+    #[serde(default)]
+    pub kind: HealthCheckKind,
+    /// defaults to the port of the backend, for http checks this is usually the graylog api port (9000)
+    pub port: Option<u16>,
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+    #[serde(default = "default_health_check_interval_seconds")]
+    pub interval_seconds: u64,
+    #[serde(default = "default_health_check_timeout_seconds")]
+    pub timeout_seconds: u64,
+    /// consecutive successful checks before an unhealthy backend gets traffic again
+    #[serde(default = "default_health_check_rise")]
+    pub rise: u32,
+    /// consecutive failed checks before a backend is considered unhealthy
+    #[serde(default = "default_health_check_fall")]
+    pub fall: u32,
+}
+
+/// Stops sending to a backend after consecutive send failures (including icmp port unreachable), then probes it before restoring it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CircuitBreaker {
+    /// failed sends within failure_window_seconds that open the circuit
+    #[serde(default = "default_circuit_breaker_failure_threshold")]
+    pub failure_threshold: u32,
+    #[serde(default = "default_circuit_breaker_failure_window_seconds")]
+    pub failure_window_seconds: u64,
+    /// how long an open circuit keeps all traffic away before the first probe
+    #[serde(default = "default_circuit_breaker_open_seconds")]
+    pub open_seconds: u64,
+    /// successful probes (at most one per second) needed to close the circuit again
+    #[serde(default = "default_circuit_breaker_half_open_successes")]
+    pub half_open_successes: u32,
+}
+
+/// Finds the nodes of a graylog cluster through its rest api and makes every node that is processing messages a backend.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GraylogDiscovery {
+    /// the api of one or more nodes ("http://graylog.internal:9000"), asked in order until one answers
+    pub urls: Vec<String>,
+    /// for an access token, use the token as username and "token" as password
+    #[serde(skip_serializing)]
+    pub username: Option<String>,
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    /// the port of the gelf input, the api does not tell us that
+    pub port: u16,
+    #[serde(default)]
+    pub protocol: BackendProtocol,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    #[serde(default = "default_graylog_discovery_interval_seconds")]
+    pub interval_seconds: u64,
+}
+
+/// A secondary pool that receives a copy of (a sample of) all messages, without affecting the primary backends.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Mirror {
+    #[serde(default = "default_mirror_percentage")]
+    pub percentage: u8,
+    #[serde(default = "default_mirror_queue_size")]
+    pub queue_size: usize,
+    pub backends: Vec<Backend>,
+    #[serde(default)]
+    pub balancing: Balancing,
+}
+
+/// Where to keep messages that could not be processed (bad gzip, bad json etc.) - a file, a backend or both.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeadLetter {
+    pub path: Option<String>,
+    pub backend: Option<Backend>,
+    #[serde(default = "default_dead_letter_queue_size")]
+    pub queue_size: usize,
+}
+
+/// Conditions on a message, a rule matches when all of the conditions that are set hold.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct MatchRule {
+    pub min_level: Option<u8>,
+    pub max_level: Option<u8>,
+    /// may contain * and ? wildcards
+    pub host: Option<String>,
+    pub facility: Option<String>,
+    /// field name (with leading underscore for additional fields) to expected value, "*" only requires the field to exist
+    #[serde(default)]
+    pub fields: HashMap<String,String>,
+    /// field name to a regular expression that the value has to match
+    #[serde(default)]
+    pub patterns: HashMap<String,Pattern>,
+    /// the address we received the message from has to be in this network, for example "10.1.0.0/16"
+    pub source: Option<Cidr>,
+}
+
+/// A backend pool that routes can refer to by name.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NamedPool {
+    pub name: String,
+    #[serde(default)]
+    pub backends: Vec<Backend>,
+    #[serde(default)]
+    pub backends_file: Option<String>,
+    #[serde(default)]
+    pub graylog_discovery: Option<GraylogDiscovery>,
+    #[serde(default)]
+    pub balancing: Balancing,
+}
+
+/// Spreads messages over several pools by percentage, for example to try a new cluster with a few percent of the traffic.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Split {
+    pub name: String,
+    /// "source_ip" or a message field. messages with the same key always go to the same pool,
+    /// without a key (or for messages that do not have it) every message is assigned on its own
+    pub hash_key: Option<String>,
+    pub pools: Vec<SplitShare>,
+}
+
+impl Split {
+    /// True if the split has to look at the contents of messages to pick a pool.
+    pub fn needs_message(&self) -> bool {
+        self.hash_key.as_deref().is_some_and(|x| x != "source_ip")
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SplitShare {
+    pub pool: String,
+    /// the shares are relative to each other, so they do not have to add up to 100
+    pub percent: f64,
+}
+
+/// Sends everything from the source networks to the named pool (or split). when several networks contain the sender,
+/// the most specific one wins.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Network {
+    pub pool: String,
+    pub sources: Vec<Cidr>,
+}
+
+/// Sends the messages that match the rule to the named pool (or split).
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Route {
+    pub pool: String,
+    #[serde(flatten)]
+    pub rule: MatchRule,
+}
+
+/// POSTs a summary of every message that matches one of the rules to a url.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Webhook {
+    /// incoming webhook urls often carry their token, so they are not shown with the rest of the configuration
+    #[serde(skip_serializing)]
+    pub url: String,
+    /// the webhook fires if any of these match, a webhook without rules fires for every message
+    #[serde(default)]
+    pub rules: Vec<MatchRule>,
+    #[serde(default = "default_webhook_max_per_minute")]
+    pub max_per_minute: u32,
+    /// request body with {{field}} placeholders, defaults to a json summary of the message
+    pub body: Option<String>,
+}
+
+/// Archives all messages as gzipped ndjson objects in an s3 compatible bucket, keyed by date/hour/host.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Archive {
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_archive_region")]
+    pub region: String,
+    pub access_key: String,
+    #[serde(skip_serializing)]
+    pub secret_key: String,
+    #[serde(default)]
+    pub prefix: String,
+    /// uncompressed bytes after which an object is uploaded
+    #[serde(default = "default_archive_flush_size")]
+    pub flush_size: u64,
+    #[serde(default = "default_archive_flush_interval_seconds")]
+    pub flush_interval_seconds: u64,
+    /// use http://endpoint/bucket/key rather than http://bucket.endpoint/key (minio needs this)
+    #[serde(default = "default_archive_path_style")]
+    pub path_style: bool,
 }
 
 impl Default for Configuration {
     fn default() -> Self {
         Configuration {
             web_ui_port: None,
+            web_ui_ip: None,
+            web_api_token: None,
             listen_ip: "".into(),
             listen_port: 0,
             strip_fields: vec![],
@@ -51,8 +436,28 @@ impl Default for Configuration {
             attach_source_info: false,
             allowed_source_ips: vec![],
             backends: vec![],
+            backends_file: None,
+            graylog_discovery: None,
             chunk_size: default_chunk_size(),
-            use_gzip: default_use_gzip()
+            use_gzip: default_use_gzip(),
+            oversized_message_policy: OversizedMessagePolicy::default(),
+            chunk_affinity_by_source: false,
+            reassembly_timeout_seconds: default_reassembly_timeout_seconds(),
+            reassembly_sweep_seconds: default_reassembly_sweep_seconds(),
+            balancing: Balancing::default(),
+            health_check: None,
+            circuit_breaker: None,
+            pools: vec![],
+            dns_refresh_seconds: default_dns_refresh_seconds(),
+            dns_server: None,
+            networks: vec![],
+            routes: vec![],
+            default_pool: default_pool_name(),
+            splits: vec![],
+            mirror: None,
+            dead_letter: None,
+            webhooks: vec![],
+            archive: None
         }
     }
 }
\ No newline at end of file
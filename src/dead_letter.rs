@@ -0,0 +1,101 @@
+use std::{collections::HashMap, fs::{File, OpenOptions}, io::Write, net::SocketAddr, sync::{mpsc::{SyncSender, TrySendError}, Arc}};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{balancer::Forwarder, pool::BackendServer, GelfMessage, GelfMessageWrapper, GelfPacket};
+
+// a message that we failed to process, kept exactly as we received it
+#[derive(Debug, Serialize)]
+pub struct DeadLetter {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub source: SocketAddr,
+    pub reason: String,
+    pub error: String,
+    /// hex encoded raw udp payloads, one per chunk
+    pub packets: Vec<String>
+}
+
+// the balancer side of the dead-letter output, never blocks: if the writer can not keep up we only count the failure
+pub struct DeadLetterSender {
+    sender: SyncSender<DeadLetter>
+}
+
+impl DeadLetterSender {
+
+    pub fn send(&self, packet: &GelfMessageWrapper, reason: &str, error: &anyhow::Error) {
+        let packets = match packet {
+            GelfMessageWrapper::Chunked(x) => x.chunks.iter().map(|c|to_hex(&c.data)).collect(),
+            GelfMessageWrapper::Simple(x) | GelfMessageWrapper::Stream(x) => vec![to_hex(&x.data)]
+        };
+        let letter = DeadLetter {
+            timestamp: chrono::Utc::now(),
+            source: packet.pkg_src(),
+            reason: reason.to_string(),
+            error: format!("{error:#}"),
+            packets
+        };
+        match self.sender.try_send(letter) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => log::warn!("the dead-letter queue is full, a failed message from {} is lost",packet.pkg_src()),
+            Err(TrySendError::Disconnected(_)) => log::warn!("the dead-letter thread is gone, a failed message from {} is lost",packet.pkg_src())
+        }
+    }
+}
+
+pub fn spawn(config: Arc<crate::Configuration>, backend: Option<BackendServer>) -> DeadLetterSender {
+    let dead_letter_config = config.dead_letter.as_ref().expect("spawning the dead-letter output requires a dead_letter configuration");
+    // a file that can not be opened is a configuration error, better to find out at startup than on the first failure
+    let file = dead_letter_config.path.as_ref().map(|path| {
+        OpenOptions::new().create(true).append(true).open(path)
+            .unwrap_or_else(|e| panic!("invalid configuration! failed to open the dead-letter file {path}: {e}"))
+    });
+    let (sender, receiver) = std::sync::mpsc::sync_channel::<DeadLetter>(dead_letter_config.queue_size);
+    std::thread::spawn(move||dead_letter_writer(config,receiver,file,backend));
+    DeadLetterSender { sender }
+}
+
+fn dead_letter_writer(config: Arc<crate::Configuration>, receiver: std::sync::mpsc::Receiver<DeadLetter>, mut file: Option<File>, backend: Option<BackendServer>) {
+
+    let mut forwarder = Forwarder::new();
+
+    while let Ok(letter) = receiver.recv() {
+
+        if let Some(file) = file.as_mut() {
+            let mut line = serde_json::to_vec(&letter).expect("should always be possible to serialize a dead letter");
+            line.push(b'\n');
+            if let Err(e) = file.write_all(&line) {
+                log::error!("failed to write to the dead-letter file: {e}");
+            }
+        }
+
+        if let Some(backend) = &backend {
+            let message = GelfMessage {
+                version: "1.1".into(),
+                host: letter.source.ip().to_string(),
+                short_message: format!("gelflb failed to process a message: {}",letter.reason),
+                full_message: Some(letter.packets.join("\n")),
+                timestamp: Some(format!("{:.3}",letter.timestamp.timestamp_millis() as f64 / 1000.0)),
+                level: Some(4),
+                facility: None,
+                file: None,
+                line: None,
+                additional_fields: HashMap::from([
+                    ("_gelflb_dead_letter_reason".to_string(),Value::from(letter.reason.clone())),
+                    ("_gelflb_dead_letter_error".to_string(),Value::from(letter.error.clone())),
+                    ("_gelflb_original_source_addr".to_string(),Value::from(letter.source.to_string())),
+                ])
+            };
+            let mut packet = GelfMessageWrapper::Simple(GelfPacket::new_simple(vec![],letter.source));
+            let result = packet.set_payload(message,&config).map_err(anyhow::Error::from)
+                .and_then(|()|forwarder.forward(&config,&packet,backend));
+            if let Err(e) = result {
+                log::error!("failed to send a dead letter to {}: {e}",backend.addr);
+            }
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b|format!("{b:02x}")).collect()
+}
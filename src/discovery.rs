@@ -0,0 +1,336 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr, ToSocketAddrs}, path::{Path, PathBuf}, sync::{mpsc::{Receiver, RecvTimeoutError, Sender}, Arc}, time::{Duration, Instant}};
+
+use anyhow::Context;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::{configuration::{Backend, GraylogDiscovery}, dns, graylog, pool::{BackendServer, Pool}};
+
+const FILE_SETTLE_TIME: Duration = Duration::from_millis(200);
+
+// one address that a backend entry stands for
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Resolved {
+    addr: SocketAddr,
+    weight: u32,
+    priority: u32
+}
+
+// turns the configured backends in to servers. a name that resolves to several addresses becomes one backend per address,
+// each with the weight of the entry. srv names become one backend per record and address.
+pub fn resolve(entries: &[Backend], config: &crate::Configuration) -> anyhow::Result<Vec<BackendServer>> {
+    let mut resolved = vec![];
+    for entry in entries {
+        resolved.push(resolve_entry(entry,config)?);
+    }
+    Ok(to_servers(entries,&resolved,config))
+}
+
+fn resolve_entry(entry: &Backend, config: &crate::Configuration) -> anyhow::Result<Vec<Resolved>> {
+    if let Some(srv) = &entry.srv {
+        return resolve_srv(entry,srv,config)
+    }
+    if entry.ip.is_empty() {
+        anyhow::bail!("a backend needs either an ip or an srv name: {:?}",entry)
+    }
+    let addresses : Vec<Resolved> = format!("{}:{}", entry.ip, entry.port).to_socket_addrs().context(format!("to_socket_addr for {:?}",entry))?
+        .map(|addr| Resolved { addr, weight: entry.weight, priority: entry.priority })
+        .collect();
+    if addresses.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "No IP addresses found.")).context(format!("configuring backend: {:?}",entry))
+    }
+    Ok(addresses)
+}
+
+// srv priorities are added to the priority of the entry, so lower still means preferred. weight 0 is treated like 1,
+// as otherwise those records would never get anything.
+fn resolve_srv(entry: &Backend, name: &str, config: &crate::Configuration) -> anyhow::Result<Vec<Resolved>> {
+    let server = match &config.dns_server {
+        Some(x) => x.parse::<SocketAddr>().or_else(|_| x.parse::<IpAddr>().map(|ip|SocketAddr::new(ip,53)))
+            .context(format!("invalid dns_server {x}"))?,
+        None => dns::system_nameserver()?
+    };
+    let answer = dns::lookup_srv(name,server)?;
+    let mut resolved = vec![];
+    // a target of "." means that the service is explicitly not available
+    for record in answer.records.iter().filter(|x|!x.target.is_empty()) {
+        let ips : Vec<IpAddr> = match answer.addresses.get(&record.target) {
+            Some(ips) => ips.clone(),
+            None => (record.target.as_str(),record.port).to_socket_addrs().context(format!("resolving srv target {}",record.target))?
+                .map(|x|x.ip()).collect()
+        };
+        for ip in ips {
+            resolved.push(Resolved {
+                addr: SocketAddr::new(ip,record.port),
+                weight: record.weight.max(1) as u32,
+                priority: entry.priority + record.priority as u32
+            });
+        }
+    }
+    if resolved.is_empty() {
+        anyhow::bail!("the srv name {name} has no usable records")
+    }
+    Ok(resolved)
+}
+
+// the health check and circuit breaker settings are taken from the configuration
+fn to_servers(entries: &[Backend], resolved: &[Vec<Resolved>], config: &crate::Configuration) -> Vec<BackendServer> {
+    let mut servers : Vec<BackendServer> = vec![];
+    for (entry,resolved) in entries.iter().zip(resolved) {
+        for x in resolved {
+            // two names for the same node should not give it twice the traffic
+            if servers.iter().any(|s|s.addr == x.addr && s.protocol == entry.protocol) {
+                continue
+            }
+            servers.push(BackendServer::new(x.addr, entry.protocol, x.weight)
+                .with_priority(x.priority)
+                .with_drain(entry.drain)
+                .with_health_check(entry.health_check.clone().or_else(||config.health_check.clone()))
+                .with_circuit_breaker(config.circuit_breaker.clone()));
+        }
+    }
+    servers
+}
+
+// keeps the backends of a pool up to date: names are resolved again periodically, the backend file (if any) is watched
+// and the graylog cluster (if any) is asked for its nodes. the file and the cluster are read once before this returns,
+// so that the pool is complete before the first message arrives. the updates only start with Discoverer::spawn, once we
+// know whether messages are reassembled, which depends on the backends that were found here.
+pub fn discover(config: Arc<crate::Configuration>, pool: Arc<Pool>, entries: Vec<Backend>, file: Option<String>, graylog: Option<GraylogDiscovery>) -> anyhow::Result<Option<Discoverer>> {
+    let has_names = config.dns_refresh_seconds > 0 && entries.iter().any(|x|x.srv.is_some() || x.ip.parse::<IpAddr>().is_err());
+    if !has_names && file.is_none() && graylog.is_none() {
+        return Ok(None)
+    }
+
+    let (sender, receiver) = std::sync::mpsc::channel::<()>();
+    let watcher = match &file {
+        Some(file) => Some(watch_file(file,sender.clone()).context(format!("failed to watch {file}"))?),
+        None => None
+    };
+
+    // without names to resolve or a cluster to ask we only wake up for the file
+    let mut interval = Duration::from_secs(if has_names { config.dns_refresh_seconds } else { 3600 });
+    if let Some(graylog) = &graylog {
+        interval = interval.min(Duration::from_secs(graylog.interval_seconds.max(1)));
+    }
+
+    let mut discoverer = Discoverer {
+        config, pool, entries, file, file_entries: vec![], graylog, cluster_entries: vec![], last_poll: Instant::now(), last_known: HashMap::new(),
+        stream_backends_allowed: true, interval, receiver, _sender: sender, _watcher: watcher
+    };
+    if discoverer.file.is_some() || discoverer.graylog.is_some() {
+        discoverer.read_file();
+        discoverer.poll_graylog();
+        discoverer.refresh();
+    }
+    Ok(Some(discoverer))
+}
+
+// we watch the directory rather than the file, as the file is usually replaced by renaming a new one over it
+fn watch_file(file: &str, sender: Sender<()>) -> notify::Result<RecommendedWatcher> {
+    let path = Path::new(file);
+    let file_name = path.file_name().map(|x|x.to_os_string());
+    let directory = match path.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+        _ => PathBuf::from(".")
+    };
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if event.paths.iter().any(|x|x.file_name().map(|x|x.to_os_string()) == file_name) {
+                let _ = sender.send(());
+            }
+        }
+    })?;
+    watcher.watch(&directory,RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+pub struct Discoverer {
+    config: Arc<crate::Configuration>,
+    pool: Arc<Pool>,
+    /// the backends from the configuration file
+    entries: Vec<Backend>,
+    file: Option<String>,
+    /// the backends from the backend file as it was the last time it could be read
+    file_entries: Vec<Backend>,
+    graylog: Option<GraylogDiscovery>,
+    /// the nodes of the graylog cluster as they were the last time it answered
+    cluster_entries: Vec<Backend>,
+    last_poll: Instant,
+    /// what each entry resolved to the last time it worked, so that a dns hiccup does not empty the pool
+    last_known: HashMap<(Option<String>,String,u16),Vec<Resolved>>,
+    /// tcp and http backends only get complete messages, they can not be added later if we pass chunks through
+    stream_backends_allowed: bool,
+    interval: Duration,
+    receiver: Receiver<()>,
+    /// keeps the channel open when there is no file
+    _sender: Sender<()>,
+    /// the watcher stops when it is dropped
+    _watcher: Option<RecommendedWatcher>
+}
+
+impl Discoverer {
+
+    pub fn spawn(mut self, stream_backends_allowed: bool) {
+        self.stream_backends_allowed = stream_backends_allowed;
+        std::thread::spawn(move|| loop {
+            match self.receiver.recv_timeout(self.interval) {
+                Ok(()) => {
+                    // editors and config management tend to write files in several steps, so we wait for them to finish
+                    std::thread::sleep(FILE_SETTLE_TIME);
+                    while self.receiver.try_recv().is_ok() {}
+                    self.read_file();
+                },
+                Err(RecvTimeoutError::Timeout) => if self.graylog.as_ref().is_some_and(|x|self.last_poll.elapsed().as_secs() >= x.interval_seconds) {
+                    self.poll_graylog();
+                },
+                Err(RecvTimeoutError::Disconnected) => return
+            }
+            self.refresh();
+        });
+    }
+
+    // a file that can not be read or parsed is logged and ignored, we keep using what it said before
+    fn read_file(&mut self) {
+        let Some(file) = &self.file else { return };
+        let result = std::fs::read_to_string(file).context("failed to read the file")
+            .and_then(|x| serde_json::from_str::<Vec<Backend>>(&x).context("failed to parse the file"));
+        match result {
+            Ok(entries) => self.file_entries = entries,
+            Err(e) => log::error!("keeping the backends of pool {} as they are, {file} is not usable: {e:#}",self.pool.name)
+        }
+    }
+
+    // a cluster that can not be reached is logged and ignored, we keep the nodes it had before
+    fn poll_graylog(&mut self) {
+        let Some(settings) = &self.graylog else { return };
+        self.last_poll = Instant::now();
+        match graylog::processing_nodes(settings) {
+            Ok(hosts) => self.cluster_entries = hosts.into_iter().map(|host| Backend {
+                ip: host,
+                port: settings.port,
+                srv: None,
+                protocol: settings.protocol,
+                weight: settings.weight,
+                priority: 0,
+                health_check: None,
+                drain: false
+            }).collect(),
+            Err(e) => log::error!("keeping the graylog nodes of pool {} as they are: {e:#}",self.pool.name)
+        }
+    }
+
+    fn refresh(&mut self) {
+        let entries : Vec<Backend> = self.entries.iter().chain(&self.file_entries).chain(&self.cluster_entries).cloned().collect();
+        let resolved : Vec<Vec<Resolved>> = entries.iter().map(|entry| {
+            let key = (entry.srv.clone(),entry.ip.clone(),entry.port);
+            match resolve_entry(entry,&self.config) {
+                Ok(resolved) => {
+                    self.last_known.insert(key,resolved.clone());
+                    resolved
+                },
+                Err(e) => {
+                    log::warn!("failed to resolve backend {}, keeping the addresses we already know: {e:#}",entry.srv.as_ref().unwrap_or(&entry.ip));
+                    self.last_known.get(&key).cloned().unwrap_or_default()
+                }
+            }
+        }).collect();
+        let servers = to_servers(&entries,&resolved,&self.config).into_iter().filter(|x| match check_backend(x,&self.config,self.stream_backends_allowed) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("ignoring backend {} of pool {}: {e}",x.addr,self.pool.name);
+                false
+            }
+        }).collect();
+        self.pool.set_backends(servers);
+    }
+}
+
+// what the configuration is checked for at startup, for backends that show up later
+pub fn check_backend(backend: &BackendServer, config: &crate::Configuration, stream_backends_allowed: bool) -> anyhow::Result<()> {
+    if backend.protocol.is_stream() && !stream_backends_allowed {
+        anyhow::bail!("tcp and http backends need chunked messages to be reassembled, which was not needed when we started. restart to add them")
+    }
+    // raw packets keep the address family of the sender
+    let listen_ip = config.listen_ip.parse::<IpAddr>();
+    if config.transparent && listen_ip.is_ok_and(|x| x.is_ipv4() != backend.addr.is_ipv4()) {
+        if backend.addr.is_ipv4() {
+            anyhow::bail!("you cannot use ipv4 backends while listening on ipv6 when you use transparent mode.")
+        } else {
+            anyhow::bail!("you cannot use ipv6 backends while listening on ipv4 when you use transparent mode.")
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{configuration::{BackendProtocol, Balancing}, graylog::tests::{mock_graylog, settings, CLUSTER, NODES}};
+
+    fn config(transparent: bool) -> Arc<crate::Configuration> {
+        Arc::new(toml::from_str(&format!("listen_ip = \"127.0.0.1\"\nlisten_port = 0\ntransparent = {transparent}")).unwrap())
+    }
+
+    fn pool() -> Arc<Pool> {
+        Arc::new(Pool::new("test",vec![],false,Duration::from_secs(10),Balancing::default()))
+    }
+
+    fn addresses(pool: &Pool) -> Vec<String> {
+        let mut addresses : Vec<String> = pool.backends().iter().map(|x|x.addr.to_string()).collect();
+        addresses.sort();
+        addresses
+    }
+
+    #[test]
+    fn backends_from_the_file_that_do_not_fit_are_left_out() {
+        let file = std::env::temp_dir().join(format!("gelflb-discovery-{}.json",std::process::id()));
+        std::fs::write(&file,r#"[
+            {"ip": "10.0.0.7", "port": 12201},
+            {"ip": "10.0.0.8", "port": 12201, "protocol": "tcp"},
+            {"ip": "fd00::9", "port": 12201}
+        ]"#).unwrap();
+        let pool = pool();
+        let mut discoverer = discover(config(true),pool.clone(),vec![],Some(file.to_string_lossy().into()),None).unwrap().unwrap();
+        // at startup a tcp backend is what makes us reassemble messages, an ipv6 one can never be used in transparent mode on ipv4
+        assert_eq!(addresses(&pool), vec!["10.0.0.7:12201","10.0.0.8:12201"]);
+
+        discoverer.stream_backends_allowed = false;
+        discoverer.read_file();
+        discoverer.refresh();
+        assert_eq!(addresses(&pool), vec!["10.0.0.7:12201"]);
+
+        let pool = self::pool();
+        discover(config(false),pool.clone(),vec![],Some(file.to_string_lossy().into()),None).unwrap();
+        assert_eq!(addresses(&pool), vec!["10.0.0.7:12201","10.0.0.8:12201","[fd00::9]:12201"]);
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn graylog_nodes_that_do_not_fit_are_left_out() {
+        let url = mock_graylog(Some(NODES),Some(CLUSTER));
+        let mut tcp = settings(vec![url.clone()]);
+        tcp.protocol = BackendProtocol::Tcp;
+        let pool = pool();
+        let mut discoverer = discover(config(true),pool.clone(),vec![],None,Some(tcp)).unwrap().unwrap();
+        assert_eq!(addresses(&pool), vec!["10.0.0.1:12201"]);
+
+        discoverer.stream_backends_allowed = false;
+        discoverer.poll_graylog();
+        discoverer.refresh();
+        assert!(addresses(&pool).is_empty());
+
+        let pool = self::pool();
+        discover(config(false),pool.clone(),vec![],None,Some(settings(vec![url]))).unwrap();
+        assert_eq!(addresses(&pool), vec!["10.0.0.1:12201","[fd00::5]:12201"]);
+    }
+
+    #[test]
+    fn configured_backends_are_checked_like_discovered_ones() {
+        let backend = |addr: &str, protocol| BackendServer::new(addr.parse().unwrap(),protocol,1);
+        assert!(check_backend(&backend("10.0.0.1:12201",BackendProtocol::Udp),&config(true),false).is_ok());
+        assert!(check_backend(&backend("[fd00::1]:12201",BackendProtocol::Udp),&config(true),false).is_err());
+        assert!(check_backend(&backend("[fd00::1]:12201",BackendProtocol::Udp),&config(false),false).is_ok());
+        assert!(check_backend(&backend("10.0.0.1:12201",BackendProtocol::Http),&config(false),false).is_err());
+        assert!(check_backend(&backend("10.0.0.1:12201",BackendProtocol::Http),&config(false),true).is_ok());
+    }
+}
@@ -0,0 +1,295 @@
+use std::{collections::HashMap, io::{Read, Write}, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket}, time::Duration};
+
+use anyhow::Context;
+
+const TIMEOUT: Duration = Duration::from_secs(3);
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String
+}
+
+/// The answer to an srv query: the records and whatever addresses the server sent along for their targets.
+#[derive(Debug, Default)]
+pub struct SrvAnswer {
+    pub records: Vec<SrvRecord>,
+    pub addresses: HashMap<String, Vec<IpAddr>>
+}
+
+// the first nameserver from resolv.conf, which is what the system resolver would ask as well
+pub fn system_nameserver() -> anyhow::Result<SocketAddr> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").context("failed to read /etc/resolv.conf")?;
+    let ip = resolv_conf.lines()
+        .filter_map(|x|x.trim().strip_prefix("nameserver"))
+        .find_map(|x|x.trim().parse::<IpAddr>().ok())
+        .ok_or_else(||anyhow::anyhow!("there is no nameserver in /etc/resolv.conf"))?;
+    Ok(SocketAddr::new(ip,53))
+}
+
+// minimal dns client, just enough for srv lookups: one question, recursion desired, tcp if the answer does not fit in udp
+pub fn lookup_srv(name: &str, server: SocketAddr) -> anyhow::Result<SrvAnswer> {
+    let id = (std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.subsec_nanos() & 0xffff) as u16;
+    let query = build_query(id, name, TYPE_SRV)?;
+
+    let socket = UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.connect(server)?;
+    socket.send(&query)?;
+    let mut buf = [0u8; 4096];
+    let response = loop {
+        let len = socket.recv(&mut buf).context(format!("no answer from {server}"))?;
+        // anything with another id is a late answer to an earlier query
+        if len >= 2 && buf[..2] == id.to_be_bytes() {
+            break buf[..len].to_vec()
+        }
+    };
+
+    let truncated = response.len() > 2 && response[2] & 0x02 != 0;
+    let response = if truncated { query_tcp(&query, server)? } else { response };
+    parse_srv_answer(&response).context(format!("invalid srv answer for {name}"))
+}
+
+fn query_tcp(query: &[u8], server: SocketAddr) -> anyhow::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&server, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.write_all(&(query.len() as u16).to_be_bytes())?;
+    stream.write_all(query)?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+    Ok(response)
+}
+
+fn build_query(id: u16, name: &str, record_type: u16) -> anyhow::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00]); // recursion desired
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // one question
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("invalid dns name {name}")
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&[0, 1]); // class IN
+    Ok(query)
+}
+
+fn parse_srv_answer(message: &[u8]) -> anyhow::Result<SrvAnswer> {
+    if message.len() < 12 {
+        anyhow::bail!("the answer is too short")
+    }
+    let rcode = message[3] & 0x0f;
+    if rcode != 0 {
+        anyhow::bail!("the server answered with rcode {rcode}")
+    }
+    let count = |i: usize| u16::from_be_bytes([message[i], message[i + 1]]) as usize;
+    let (questions, records) = (count(4), count(6) + count(8) + count(10));
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        read_name(message, &mut pos)?;
+        pos += 4;
+    }
+
+    let mut answer = SrvAnswer::default();
+    for _ in 0..records {
+        let owner = read_name(message, &mut pos)?;
+        let header = message.get(pos..pos + 10).ok_or_else(||anyhow::anyhow!("a record is cut off"))?;
+        let record_type = u16::from_be_bytes([header[0], header[1]]);
+        let rdata_len = u16::from_be_bytes([header[8], header[9]]) as usize;
+        pos += 10;
+        let rdata = message.get(pos..pos + rdata_len).ok_or_else(||anyhow::anyhow!("a record is cut off"))?;
+        match record_type {
+            TYPE_SRV if rdata_len >= 7 => {
+                let mut target_pos = pos + 6;
+                answer.records.push(SrvRecord {
+                    priority: u16::from_be_bytes([rdata[0], rdata[1]]),
+                    weight: u16::from_be_bytes([rdata[2], rdata[3]]),
+                    port: u16::from_be_bytes([rdata[4], rdata[5]]),
+                    target: read_name(message, &mut target_pos)?
+                });
+            },
+            TYPE_A if rdata_len == 4 => {
+                let ip = Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]);
+                answer.addresses.entry(owner).or_default().push(IpAddr::V4(ip));
+            },
+            TYPE_AAAA if rdata_len == 16 => {
+                let bits : [u8; 16] = rdata.try_into().expect("the length was checked");
+                answer.addresses.entry(owner).or_default().push(IpAddr::V6(Ipv6Addr::from(bits)));
+            },
+            _ => {}
+        }
+        pos += rdata_len;
+    }
+    Ok(answer)
+}
+
+// names are lower cased and without the trailing dot. compression pointers are followed, but only to before where the
+// last one pointed to, so that we can not loop.
+fn read_name(message: &[u8], pos: &mut usize) -> anyhow::Result<String> {
+    let mut labels : Vec<String> = vec![];
+    let mut cursor = *pos;
+    let mut limit = *pos;
+    let mut jumped = false;
+    loop {
+        let len = *message.get(cursor).ok_or_else(||anyhow::anyhow!("a name is cut off"))? as usize;
+        if len == 0 {
+            cursor += 1;
+            break
+        }
+        if len & 0xc0 == 0xc0 {
+            let pointer = ((len & 0x3f) << 8) | *message.get(cursor + 1).ok_or_else(||anyhow::anyhow!("a name is cut off"))? as usize;
+            if pointer >= limit {
+                anyhow::bail!("invalid compression pointer")
+            }
+            limit = pointer;
+            if !jumped {
+                *pos = cursor + 2;
+                jumped = true;
+            }
+            cursor = pointer;
+            continue
+        }
+        let label = message.get(cursor + 1..cursor + 1 + len).ok_or_else(||anyhow::anyhow!("a name is cut off"))?;
+        labels.push(String::from_utf8_lossy(label).to_lowercase());
+        cursor += 1 + len;
+    }
+    if !jumped {
+        *pos = cursor;
+    }
+    Ok(labels.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    const NAME: &str = "_gelf._udp.example.com";
+
+    // an answer the way real servers send it: the owner names point at the question, the targets share its domain
+    // and the addresses of the targets come along as additional records that point at the targets
+    fn srv_answer(id: u16) -> Vec<u8> {
+        let mut message = build_query(id, NAME, TYPE_SRV).unwrap();
+        message[2..4].copy_from_slice(&[0x81, 0x80]);
+        message[6..12].copy_from_slice(&[0, 2, 0, 0, 0, 2]);
+        let domain = 12 + 1 + "_gelf".len() + 1 + "_udp".len();
+        let mut targets = vec![];
+        for (priority, weight, port, target) in [(10u16, 60u16, 12201u16, "Node1"), (20, 40, 12202, "node2")] {
+            let mut rdata = [priority.to_be_bytes(), weight.to_be_bytes(), port.to_be_bytes()].concat();
+            targets.push(message.len() + 12 + rdata.len());
+            rdata.push(target.len() as u8);
+            rdata.extend_from_slice(target.as_bytes());
+            rdata.extend_from_slice(&[0xc0, domain as u8]);
+            message.extend_from_slice(&[0xc0, 12]);
+            push_record(&mut message, TYPE_SRV, &rdata);
+        }
+        for (target, ip) in targets.iter().zip([[10, 0, 0, 1], [10, 0, 0, 2]]) {
+            message.extend_from_slice(&[0xc0, *target as u8]);
+            push_record(&mut message, TYPE_A, &ip);
+        }
+        message
+    }
+
+    fn push_record(message: &mut Vec<u8>, record_type: u16, rdata: &[u8]) {
+        message.extend_from_slice(&record_type.to_be_bytes());
+        message.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+        message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        message.extend_from_slice(rdata);
+    }
+
+    fn assert_expected(answer: &SrvAnswer) {
+        assert_eq!(answer.records, vec![
+            SrvRecord { priority: 10, weight: 60, port: 12201, target: "node1.example.com".into() },
+            SrvRecord { priority: 20, weight: 40, port: 12202, target: "node2.example.com".into() }
+        ]);
+        assert_eq!(answer.addresses["node1.example.com"], vec![IpAddr::from([10, 0, 0, 1])]);
+        assert_eq!(answer.addresses["node2.example.com"], vec![IpAddr::from([10, 0, 0, 2])]);
+    }
+
+    // answers one query over udp, truncated answers send the client on to tcp on the same port
+    fn stub_server(truncate: bool) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = truncate.then(||TcpListener::bind(addr).unwrap());
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (len, client) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[12..len], &build_query(0, NAME, TYPE_SRV).unwrap()[12..], "unexpected question");
+            let id = u16::from_be_bytes([buf[0], buf[1]]);
+            // a late answer to an earlier query should be ignored
+            socket.send_to(&srv_answer(id.wrapping_add(1)), client).unwrap();
+            match listener {
+                None => {
+                    socket.send_to(&srv_answer(id), client).unwrap();
+                },
+                Some(listener) => {
+                    let mut truncated = srv_answer(id)[..12].to_vec();
+                    truncated[2] |= 0x02;
+                    truncated[6..12].fill(0);
+                    socket.send_to(&truncated, client).unwrap();
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut len = [0u8; 2];
+                    stream.read_exact(&mut len).unwrap();
+                    let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+                    stream.read_exact(&mut query).unwrap();
+                    let answer = srv_answer(u16::from_be_bytes([query[0], query[1]]));
+                    stream.write_all(&(answer.len() as u16).to_be_bytes()).unwrap();
+                    stream.write_all(&answer).unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn looks_up_srv_records_over_udp() {
+        assert_expected(&lookup_srv(NAME, stub_server(false)).unwrap());
+    }
+
+    #[test]
+    fn falls_back_to_tcp_for_truncated_answers() {
+        assert_expected(&lookup_srv(NAME, stub_server(true)).unwrap());
+    }
+
+    #[test]
+    fn truncated_answers_are_errors() {
+        let answer = srv_answer(1);
+        assert_expected(&parse_srv_answer(&answer).unwrap());
+        for len in 0..answer.len() {
+            assert!(parse_srv_answer(&answer[..len]).is_err(), "an answer cut off after {len} bytes was accepted");
+        }
+    }
+
+    #[test]
+    fn looping_compression_pointers_are_errors() {
+        let mut answer = srv_answer(1);
+        answer[6..12].copy_from_slice(&[0, 1, 0, 0, 0, 0]);
+        answer.truncate(12 + NAME.len() + 2 + 4);
+        let record = answer.len();
+        // a pointer to itself
+        let mut looping = answer.clone();
+        looping.extend_from_slice(&[0xc0, record as u8]);
+        assert!(parse_srv_answer(&looping).is_err());
+        // a label followed by a pointer back to that label
+        let mut looping = answer.clone();
+        looping.extend_from_slice(&[1, b'a', 0xc0, record as u8]);
+        assert!(parse_srv_answer(&looping).is_err());
+        // a pointer past the end
+        let mut looping = answer;
+        looping.extend_from_slice(&[0xff, 0xff]);
+        assert!(parse_srv_answer(&looping).is_err());
+    }
+}
@@ -1,9 +1,11 @@
-use std::{collections::HashMap, io::Read, net::SocketAddr, time::{SystemTime, UNIX_EPOCH}};
+use std::{collections::HashMap, io::Read, net::{IpAddr, SocketAddr}, time::{Instant, SystemTime, UNIX_EPOCH}};
 use anyhow::Context;
 use flate2::{bufread::GzDecoder, Compression};
 use serde::{de::{self, Visitor}, Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 
+use crate::configuration::OversizedMessagePolicy;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GelfMessage {
     pub version: String,
@@ -19,13 +21,34 @@ pub struct GelfMessage {
     pub facility: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub file: Option<String>,
-    #[serde(deserialize_with = "deserialize_line")]
+    #[serde(default, deserialize_with = "deserialize_line", skip_serializing_if = "Option::is_none")]
     pub line: Option<String>,
     #[serde(flatten)]
     pub additional_fields: HashMap<String, Value>,
 }
 
 
+impl GelfMessage {
+    /// Looks up a field by its gelf name (additional fields include the leading underscore) and returns it as a string.
+    pub fn field(&self, name: &str) -> Option<String> {
+        match name {
+            "version" => Some(self.version.clone()),
+            "host" => Some(self.host.clone()),
+            "short_message" => Some(self.short_message.clone()),
+            "full_message" => self.full_message.clone(),
+            "timestamp" => self.timestamp.clone(),
+            "level" => self.level.map(|x|x.to_string()),
+            "facility" => self.facility.clone(),
+            "file" => self.file.clone(),
+            "line" => self.line.clone(),
+            _ => self.additional_fields.get(name).map(|x| match x {
+                Value::String(s) => s.clone(),
+                other => other.to_string()
+            })
+        }
+    }
+}
+
 // Custom deserializer for the `line` field
 fn deserialize_line<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
 where
@@ -53,6 +76,13 @@ where
         {
             Ok(Some(value.to_string()))
         }
+
+        fn visit_unit<E>(self) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(None)
+        }
     }
 
     deserializer.deserialize_any(StringOrNumberVisitor)
@@ -63,7 +93,6 @@ where
 pub struct GelfPacket {
     pub data: Vec<u8>,
     pub message_id: u64,
-    #[allow(dead_code)]
     pub sequence_number: u8,
     #[allow(dead_code)]
     pub total_chunks: u8,
@@ -105,6 +134,23 @@ impl GelfPacket {
         self.data.len() >= 5 && self.data[0] == 0x1e && self.data[1] == 0x0f
     }
 
+    // senders can put anything in the chunk header, a chunk that cannot belong to a valid message is not worth keeping
+    pub fn validate_chunk(&self) -> Result<(),ChunkError> {
+        if self.data.len() < 12 {
+            return Err(ChunkError::TruncatedHeader)
+        }
+        if self.total_chunks == 0 {
+            return Err(ChunkError::NoChunks)
+        }
+        if self.total_chunks as usize > GELF_MAX_CHUNKS {
+            return Err(ChunkError::TooManyChunks(self.total_chunks))
+        }
+        if self.sequence_number >= self.total_chunks {
+            return Err(ChunkError::SequenceOutOfRange(self.sequence_number,self.total_chunks))
+        }
+        Ok(())
+    }
+
 }
 
 
@@ -127,33 +173,119 @@ pub fn parse_chunk_info(data: &[u8]) -> (u64, u8, u8) {
 
 #[derive(Debug,Clone)]
 pub struct GelfChunkedMessage { 
+    /// always in the order of their sequence numbers, whatever order they arrived in
     pub chunks : Vec<GelfPacket>,
-    pub arrival_time : chrono::DateTime<chrono::Utc>,
+    /// when the first chunk arrived
+    pub arrival_time : Instant,
     pub expected_max_chunks : usize,
-    pub id : u64
+    pub id : u64,
+    /// one bit per sequence number that has arrived
+    received : u128
 }
 impl GelfChunkedMessage {
     pub fn new(initial_packet:GelfPacket) -> Self {
         Self {
             id: initial_packet.message_id,
-            arrival_time: chrono::Utc::now(),
+            arrival_time: Instant::now(),
             expected_max_chunks: initial_packet.total_chunks as usize,
+            received: sequence_bit(initial_packet.sequence_number),
             chunks: vec![initial_packet],            
         }
     }
-    pub fn age_in_seconds(&self) -> i64 {
-        (chrono::Utc::now() - self.arrival_time).num_seconds()
+    // ids are only unique per sender, and time based ids from different senders collide easily
+    pub fn reassembly_key(&self) -> (IpAddr,u64) {
+        (self.chunks[0].source_ip.ip(), self.id)
     }
-    
+
+    // udp does not keep packets in order, so chunks are sorted in as they arrive. it can also deliver a packet twice,
+    // and a duplicate must not stand in for a chunk that is still missing.
+    pub fn add_chunk(&mut self, chunk: GelfPacket) -> Result<(),ChunkError> {
+        if chunk.total_chunks as usize != self.expected_max_chunks {
+            return Err(ChunkError::InconsistentTotal(chunk.total_chunks,self.expected_max_chunks))
+        }
+        let bit = sequence_bit(chunk.sequence_number);
+        if self.received & bit != 0 {
+            return Err(ChunkError::Duplicate(chunk.sequence_number))
+        }
+        self.received |= bit;
+        let position = self.chunks.partition_point(|x|x.sequence_number <= chunk.sequence_number);
+        self.chunks.insert(position, chunk);
+        Ok(())
+    }
+
     pub fn is_complete(&self) -> bool {
-        self.chunks.len() >= self.expected_max_chunks
+        self.received.count_ones() as usize >= self.expected_max_chunks
     }
 }
 
-#[derive(Debug)]
+// sequence numbers above 127 are rejected before chunks get here
+pub fn sequence_bit(sequence_number: u8) -> u128 {
+    1u128.checked_shl(sequence_number.into()).unwrap_or_default()
+}
+
+#[derive(Debug,Clone)]
 pub enum GelfMessageWrapper {
     Chunked(GelfChunkedMessage),
-    Simple(GelfPacket)
+    Simple(GelfPacket),
+    /// a message that is too large for udp and must be sent to a tcp backend as a null terminated frame
+    Stream(GelfPacket)
+}
+
+/// The gelf spec does not allow a message to be split in to more chunks than this.
+pub const GELF_MAX_CHUNKS: usize = 128;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PayloadError {
+    #[error("message would need {0} chunks, gelf allows at most {GELF_MAX_CHUNKS}")]
+    TooManyChunks(usize),
+    #[error("invalid gzip data: {0}")]
+    InvalidGzip(std::io::Error),
+    #[error("payload is not valid utf8")]
+    InvalidUtf8,
+    #[error("payload is not a valid gelf message: {0}")]
+    InvalidJson(serde_json::Error)
+}
+
+impl PayloadError {
+    /// Short description used when counting failures.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            PayloadError::TooManyChunks(_) => "too many chunks",
+            PayloadError::InvalidGzip(_) => "invalid gzip",
+            PayloadError::InvalidUtf8 => "invalid utf8",
+            PayloadError::InvalidJson(_) => "invalid json",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChunkError {
+    #[error("packet is too short for a chunk header")]
+    TruncatedHeader,
+    #[error("chunk says that its message has no chunks")]
+    NoChunks,
+    #[error("chunk says that its message has {0} chunks, gelf allows at most {GELF_MAX_CHUNKS}")]
+    TooManyChunks(u8),
+    #[error("chunk has sequence number {0} but its message only has {1} chunks")]
+    SequenceOutOfRange(u8,u8),
+    #[error("chunk says that its message has {0} chunks, earlier chunks said {1}")]
+    InconsistentTotal(u8,usize),
+    #[error("chunk {0} arrived more than once")]
+    Duplicate(u8)
+}
+
+impl ChunkError {
+    /// Short description used when counting dropped chunks.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ChunkError::TruncatedHeader => "truncated chunk header",
+            ChunkError::NoChunks => "chunk total of 0",
+            ChunkError::TooManyChunks(_) => "chunk total above 128",
+            ChunkError::SequenceOutOfRange(..) => "chunk sequence out of range",
+            ChunkError::InconsistentTotal(..) => "inconsistent chunk total",
+            ChunkError::Duplicate(_) => "duplicate chunk",
+        }
+    }
 }
 
 fn calculate_packet_sizes(total_size: usize, max_packet_size: usize) -> (usize, Vec<usize>) {
@@ -173,7 +305,7 @@ fn calculate_packet_sizes(total_size: usize, max_packet_size: usize) -> (usize,
     (number_of_packets, packet_sizes)
 }
 
-fn create_packets(data: &[u8], packet_sizes: &Vec<usize>, id: u64) -> Vec<Vec<u8>> {
+fn create_packets(data: &[u8], packet_sizes: &[usize], id: u64) -> Vec<Vec<u8>> {
     
     let mut packets = Vec::new();
     let mut start = 0;
@@ -192,7 +324,7 @@ fn create_packets(data: &[u8], packet_sizes: &Vec<usize>, id: u64) -> Vec<Vec<u8
 }
 
 // silly little method for generating psuedo-random message ids for chunking
-fn generate_message_id() -> u64 {
+pub fn generate_message_id() -> u64 {
     let mut message_id = [0u8; 8];
     message_id[0] = b'G';
     message_id[1] = b'L';
@@ -226,53 +358,84 @@ fn gzip_decompress(compressed_bytes: &[u8]) -> std::io::Result<Vec<u8>> {
 
 impl GelfMessageWrapper {
 
-    pub fn set_payload(&mut self,new_payload_msg:GelfMessage,config:&crate::Configuration) {
+    pub fn set_payload(&mut self,mut new_payload_msg:GelfMessage,config:&crate::Configuration) -> Result<(),PayloadError> {
 
+        loop {
 
-        let serialized = serde_json::to_string(&new_payload_msg).map_err(|e|format!("{e:?}")).expect("should always be possible to serialize gelfmsg");
-        let json_bytes = serialized.into_bytes();
+            let serialized = serde_json::to_string(&new_payload_msg).map_err(|e|format!("{e:?}")).expect("should always be possible to serialize gelfmsg");
+            let json_bytes = serialized.into_bytes();
 
-        let use_gzip = config.use_gzip.unwrap_or_default(); 
-        let mut compressed_bytes : Option<Vec<u8>> = None;
-        
-        if use_gzip {
-           let zips = gzip_compress(&json_bytes).expect("should always be possible to gzip the payload..");
-           compressed_bytes = Some(zips);
-        };
+            let use_gzip = config.use_gzip.unwrap_or_default(); 
+            let mut compressed_bytes : Option<Vec<u8>> = None;
+            
+            if use_gzip {
+               let zips = gzip_compress(&json_bytes).expect("should always be possible to gzip the payload..");
+               compressed_bytes = Some(zips);
+            };
 
-        // keep pointing to the original byte array unless using compression
-        let bytes = match &compressed_bytes {
-            Some(x) => x,
-            None => &json_bytes,
-        };
+            // keep pointing to the original byte array unless using compression
+            let bytes = match &compressed_bytes {
+                Some(x) => x,
+                None => &json_bytes,
+            };
 
-        // we only ever do this internal re-chunking if we have modified the payload of a message,
-        // otherwise we just forward the message (chunked or not) as is.
+            // we only ever do this internal re-chunking if we have modified the payload of a message,
+            // otherwise we just forward the message (chunked or not) as is.
 
-        // when we chunk, we just do it with safe upper bounds so that we can be sure that our chunks are below the set limits
-        // configured in the settings.
+            // when we chunk, we just do it with safe upper bounds so that we can be sure that our chunks are below the set limits
+            // configured in the settings.
 
-        let udp_hdr_size = 8; // Source Port (16 bits),Destination Port (16 bits),Length (16 bits),Checksum (16 bits)
-        let ip_hdr_size = 60; // ipv6 is 40 and ipv4 ranges between 20-60, so we just pick the safe value here.
-        let payload_size = bytes.len();
-        let total = payload_size + ip_hdr_size + udp_hdr_size; // [ IP [ UDP [ PAYLOAD ]]] | full size
+            let udp_hdr_size = 8; // Source Port (16 bits),Destination Port (16 bits),Length (16 bits),Checksum (16 bits)
+            let ip_hdr_size = 60; // ipv6 is 40 and ipv4 ranges between 20-60, so we just pick the safe value here.
+            let payload_size = bytes.len();
+            let total = payload_size + ip_hdr_size + udp_hdr_size; // [ IP [ UDP [ PAYLOAD ]]] | full size
 
-        // todo: make configurable
-        let max_allowed_packet_size = config.chunk_size as usize; // Maximum size of each packet in bytes
+            let max_allowed_packet_size = config.chunk_size as usize; // Maximum size of each packet in bytes
 
-        if total > max_allowed_packet_size {
+            if total <= max_allowed_packet_size {
+                log::trace!("we do not need to chunk this message as it is only going to be {} bytes in total",total);
+                *self = GelfMessageWrapper::Simple(GelfPacket::new_simple(bytes.to_vec(), self.pkg_src()));
+                return Ok(())
+            }
            
             // each byte is allowed to be max_allowed_packet_size minus 12 bytes for the gelf chunk header, 68 for udp and ip headers
             let (number_of_packets, packet_sizes) = calculate_packet_sizes(bytes.len(), max_allowed_packet_size - 68 - 12);
-        
-            //log::trace!("we need to chunk this message of {total} bytes in to {number_of_packets} chunks ({packet_sizes:?})");
 
-            let pkg_id = self.pkg_id().unwrap_or_else(||generate_message_id()); // todo: if the package was not originally chunked, we wont have an id and we should generate one
+            if number_of_packets > GELF_MAX_CHUNKS {
+                match config.oversized_message_policy {
+                    OversizedMessagePolicy::Drop => {
+                        return Err(PayloadError::TooManyChunks(number_of_packets))
+                    },
+                    OversizedMessagePolicy::Stream => {
+                        log::trace!("message would need {number_of_packets} chunks, handing it over to a stream backend instead");
+                        let mut frame = json_bytes;
+                        frame.push(0); // gelf over tcp is uncompressed json terminated by a null byte
+                        *self = GelfMessageWrapper::Stream(GelfPacket::new_simple(frame, self.pkg_src()));
+                        return Ok(())
+                    },
+                    OversizedMessagePolicy::Truncate => {
+                        let full_message = match new_payload_msg.full_message.as_mut() {
+                            Some(x) if !x.is_empty() => x,
+                            _ => return Err(PayloadError::TooManyChunks(number_of_packets))
+                        };
+                        // compression makes the final size hard to predict, so we cut a bit more than the ratio
+                        // suggests and go around again until the message fits.
+                        let mut keep = full_message.len() * GELF_MAX_CHUNKS / number_of_packets * 9 / 10;
+                        while !full_message.is_char_boundary(keep) {
+                            keep -= 1;
+                        }
+                        log::trace!("message would need {number_of_packets} chunks, truncating full_message from {} to {keep} bytes",full_message.len());
+                        full_message.truncate(keep);
+                        continue
+                    },
+                }
+            }
+        
+            let pkg_id = self.pkg_id().unwrap_or_else(generate_message_id);
             let pkg_src = self.pkg_src();
-            let pkg_arrival_time = chrono::Utc::now();
-
-            let data_for_each_pkg = create_packets(&bytes,&packet_sizes, pkg_id );
+            let pkg_arrival_time = Instant::now();
 
+            let data_for_each_pkg = create_packets(bytes,&packet_sizes, pkg_id );
 
             let packets : Vec<GelfPacket> = data_for_each_pkg.into_iter().enumerate().map(|(i,bytes)| 
                 GelfPacket::new_chunked(bytes, pkg_id, i as u8, number_of_packets as u8, pkg_src)
@@ -280,38 +443,45 @@ impl GelfMessageWrapper {
             
             let old_packet_chunk_count = match self {
                 GelfMessageWrapper::Chunked(x) => x.chunks.len(),
-                GelfMessageWrapper::Simple(_) => 1,
+                GelfMessageWrapper::Simple(_) | GelfMessageWrapper::Stream(_) => 1,
             };
             log::trace!("chunked a message in to {number_of_packets} packets: {packet_sizes:?}. (it was {old_packet_chunk_count} when we received it..)");
 
-            *self = GelfMessageWrapper::Chunked(GelfChunkedMessage { id: pkg_id, chunks: packets, arrival_time: pkg_arrival_time, expected_max_chunks: number_of_packets});
-           
+            *self = GelfMessageWrapper::Chunked(GelfChunkedMessage { id: pkg_id, chunks: packets, arrival_time: pkg_arrival_time, expected_max_chunks: number_of_packets, received: u128::MAX >> (GELF_MAX_CHUNKS - number_of_packets) });
 
-        } else {
-            log::trace!("we do not need to chunk this message as it is only going to be {} bytes in total",total);
-            *self = GelfMessageWrapper::Simple(GelfPacket::new_simple(bytes.to_vec(), self.pkg_src()))
+            return Ok(())
         }
 
     }
 
+    /// Returns the message as a gelf tcp frame: uncompressed json terminated by a null byte.
+    pub fn to_stream_frame(&self) -> anyhow::Result<Vec<u8>> {
+        if let GelfMessageWrapper::Stream(x) = self {
+            return Ok(x.data.clone())
+        }
+        let mut frame = serde_json::to_vec(&self.get_payload()?)?;
+        frame.push(0);
+        Ok(frame)
+    }
+
     pub fn pkg_id(&self) -> Option<u64> {
         match self {
             GelfMessageWrapper::Chunked(x) => Some(x.id),
-            GelfMessageWrapper::Simple(_) => None,
+            GelfMessageWrapper::Simple(_) | GelfMessageWrapper::Stream(_) => None,
         }
     }
 
     pub fn pkg_src(&self) -> SocketAddr {
         match self {
             GelfMessageWrapper::Chunked(x) => x.chunks[0].source_ip,
-            GelfMessageWrapper::Simple(x) => x.source_ip,
+            GelfMessageWrapper::Simple(x) | GelfMessageWrapper::Stream(x) => x.source_ip,
         }
     }
 
     pub fn is_chunked(&self) -> bool {
         match self {
             GelfMessageWrapper::Chunked(_) => true,
-            GelfMessageWrapper::Simple(_) => false,
+            GelfMessageWrapper::Simple(_) | GelfMessageWrapper::Stream(_) => false,
         }
     }
 
@@ -320,6 +490,7 @@ impl GelfMessageWrapper {
         let payload = match self {
             GelfMessageWrapper::Chunked(chunk_info) => {
                 let mut s = vec![];
+                // the chunks are kept in sequence order, so this is the order the sender split the payload in
                 for pkg in &chunk_info.chunks {
                     let payload = pkg.data[12..].to_vec();
                     s.extend_from_slice(&payload);
@@ -327,10 +498,11 @@ impl GelfMessageWrapper {
                 Self::convert_payload_to_utf8_string(&s)
 
             },
-            GelfMessageWrapper::Simple(pkg) => Self::convert_payload_to_utf8_string(&pkg.data).context("failed to decode packet as gelf json")
+            GelfMessageWrapper::Simple(pkg) => Self::convert_payload_to_utf8_string(&pkg.data).context("failed to decode packet as gelf json"),
+            GelfMessageWrapper::Stream(pkg) => String::from_utf8(pkg.data.strip_suffix(&[0]).unwrap_or(&pkg.data).to_vec()).map_err(|_|PayloadError::InvalidUtf8).context("failed to decode stream frame as utf8")
         }?;
 
-        let result = serde_json::from_str::<GelfMessage>(&payload).context("failed to parse payload json as gelfmessage")?;
+        let result = serde_json::from_str::<GelfMessage>(&payload).map_err(PayloadError::InvalidJson).context("failed to parse payload json as gelfmessage")?;
         
         Ok(result)
         
@@ -343,10 +515,10 @@ impl GelfMessageWrapper {
         if is_gzipped {
             let mut decoder = GzDecoder::new(&*payload_bytes);
             let mut json_data = String::new();
-            decoder.read_to_string(&mut json_data).context("failed to read gzipped data")?;
+            decoder.read_to_string(&mut json_data).map_err(PayloadError::InvalidGzip).context("failed to read gzipped data")?;
             Ok(json_data)
         } else {
-            let json_data = serde_json::from_slice(&payload_bytes)?;
+            let json_data = serde_json::from_slice(&payload_bytes).map_err(PayloadError::InvalidJson)?;
             Ok(json_data)
         }
 
@@ -354,4 +526,204 @@ impl GelfMessageWrapper {
 
     
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pool::mix, reassembly::Reassembly};
+
+    fn message() -> GelfMessage {
+        // hex of mixed numbers does not compress much, so the gzipped message needs plenty of chunks
+        let full_message : String = (0..400u64).map(|i| format!("{:016x}",mix(i))).collect();
+        GelfMessage {
+            version: "1.1".into(), host: "example".into(), short_message: "hello".into(), full_message: Some(full_message),
+            timestamp: None, level: Some(6), facility: None, file: None, line: Some("7".into()),
+            additional_fields: HashMap::from([("_user".to_string(),Value::from("alice"))])
+        }
+    }
+
+    // the message gzipped and chunked the way senders do it, each chunk as it comes out of the receive loop
+    fn chunks(message: GelfMessage) -> Vec<GelfChunkedMessage> {
+        let config = crate::Configuration { use_gzip: Some(true), chunk_size: 200, ..Default::default() };
+        let mut packet = GelfMessageWrapper::Simple(GelfPacket::new_simple(vec![],"192.168.1.10:5000".parse().unwrap()));
+        packet.set_payload(message,&config).unwrap();
+        let GelfMessageWrapper::Chunked(chunked) = packet else { panic!("the message should need more than one chunk") };
+        assert!(chunked.chunks.len() > 5, "only {} chunks",chunked.chunks.len());
+        chunked.chunks.into_iter().map(|chunk| {
+            chunk.validate_chunk().unwrap();
+            GelfChunkedMessage::new(chunk)
+        }).collect()
+    }
+
+    fn reassemble(chunks: Vec<GelfChunkedMessage>) -> GelfMessage {
+        let reassembly = Reassembly::new(std::time::Duration::from_secs(10));
+        let nr_of_chunks = chunks.len();
+        let mut completed = None;
+        for (i,chunk) in chunks.into_iter().enumerate() {
+            let result = reassembly.add(chunk).unwrap();
+            assert_eq!(result.is_some(), i == nr_of_chunks - 1, "the message should complete with its last chunk only");
+            completed = result;
+        }
+        assert_eq!(reassembly.pending(), 0);
+        let completed = completed.unwrap();
+        let sequence_numbers : Vec<u8> = completed.chunks.iter().map(|x|x.sequence_number).collect();
+        assert_eq!(sequence_numbers, (0..nr_of_chunks as u8).collect::<Vec<_>>());
+        GelfMessageWrapper::Chunked(completed).get_payload().expect("the reassembled payload should be a valid gzipped message")
+    }
+
+    fn assert_original(reassembled: GelfMessage) {
+        let original = message();
+        assert_eq!(reassembled.short_message, original.short_message);
+        assert_eq!(reassembled.full_message, original.full_message);
+        assert_eq!(reassembled.line, original.line);
+        assert_eq!(reassembled.field("_user").as_deref(), Some("alice"));
+    }
+
+    // a gzipped message with this full_message needs well over 128 chunks of 200 bytes
+    fn oversized(policy: OversizedMessagePolicy) -> (GelfMessageWrapper, Result<(),PayloadError>) {
+        let full_message : String = (0..2000u64).map(|i| format!("{:016x}",mix(i))).collect();
+        let message = GelfMessage { full_message: Some(full_message), ..message() };
+        let config = crate::Configuration { use_gzip: Some(true), chunk_size: 200, oversized_message_policy: policy, ..Default::default() };
+        let mut packet = GelfMessageWrapper::Simple(GelfPacket::new_simple(vec![],"192.168.1.10:5000".parse().unwrap()));
+        let result = packet.set_payload(message,&config);
+        (packet, result)
+    }
+
+    #[test]
+    fn oversized_messages_are_truncated_to_fit() {
+        let (packet, result) = oversized(OversizedMessagePolicy::Truncate);
+        result.unwrap();
+        let GelfMessageWrapper::Chunked(chunked) = &packet else { panic!("a truncated message should still be chunked") };
+        assert!(chunked.chunks.len() <= GELF_MAX_CHUNKS, "{} chunks",chunked.chunks.len());
+        assert!(chunked.chunks.len() > GELF_MAX_CHUNKS / 2, "truncated much more than needed, {} chunks",chunked.chunks.len());
+        assert!(chunked.chunks.iter().all(|x| x.total_chunks as usize == chunked.chunks.len()));
+        let truncated = packet.get_payload().unwrap();
+        let original : String = (0..2000u64).map(|i| format!("{:016x}",mix(i))).collect();
+        let full_message = truncated.full_message.unwrap();
+        assert!(full_message.len() < original.len() && original.starts_with(&full_message));
+        assert_eq!(truncated.short_message, "hello");
+    }
+
+    #[test]
+    fn oversized_messages_without_full_message_can_not_be_truncated() {
+        let short_message : String = (0..2000u64).map(|i| format!("{:016x}",mix(i))).collect();
+        let message = GelfMessage { short_message, full_message: None, ..message() };
+        let config = crate::Configuration { use_gzip: Some(true), chunk_size: 200, oversized_message_policy: OversizedMessagePolicy::Truncate, ..Default::default() };
+        let mut packet = GelfMessageWrapper::Simple(GelfPacket::new_simple(vec![],"192.168.1.10:5000".parse().unwrap()));
+        assert!(matches!(packet.set_payload(message,&config), Err(PayloadError::TooManyChunks(x)) if x > GELF_MAX_CHUNKS));
+    }
+
+    #[test]
+    fn oversized_messages_are_streamed() {
+        let (packet, result) = oversized(OversizedMessagePolicy::Stream);
+        result.unwrap();
+        let GelfMessageWrapper::Stream(frame) = &packet else { panic!("the message should be handed over as a stream frame") };
+        assert_eq!(frame.data.last(), Some(&0));
+        assert_eq!(packet.to_stream_frame().unwrap(), frame.data);
+        let streamed = packet.get_payload().unwrap();
+        assert_eq!(streamed.full_message.unwrap().len(), 2000 * 16);
+    }
+
+    #[test]
+    fn oversized_messages_are_dropped() {
+        let (_, result) = oversized(OversizedMessagePolicy::Drop);
+        match result {
+            Err(e @ PayloadError::TooManyChunks(x)) => {
+                assert!(x > GELF_MAX_CHUNKS);
+                assert_eq!(e.reason(), "too many chunks");
+            },
+            other => panic!("expected too many chunks, got {other:?}")
+        }
+    }
+
+    #[test]
+    fn chunks_in_order_are_reassembled() {
+        assert_original(reassemble(chunks(message())));
+    }
+
+    #[test]
+    fn reversed_chunks_are_reassembled() {
+        let mut chunks = chunks(message());
+        chunks.reverse();
+        assert_original(reassemble(chunks));
+    }
+
+    #[test]
+    fn shuffled_chunks_are_reassembled() {
+        for seed in 0..20 {
+            let mut chunks = chunks(message());
+            chunks.sort_by_key(|x| mix(x.chunks[0].sequence_number as u64 ^ mix(seed)));
+            assert_original(reassemble(chunks));
+        }
+    }
+
+    #[test]
+    fn duplicate_chunks_do_not_complete_a_message() {
+        let reassembly = Reassembly::new(std::time::Duration::from_secs(10));
+        let mut chunks = chunks(message());
+        let last = chunks.pop().unwrap();
+        for chunk in chunks.iter().cloned() {
+            assert!(reassembly.add(chunk).unwrap().is_none());
+        }
+        assert!(matches!(reassembly.add(chunks[0].clone()), Err(ChunkError::Duplicate(0))));
+        assert!(matches!(reassembly.add(chunks[1].clone()), Err(ChunkError::Duplicate(1))));
+        let completed = reassembly.add(last).unwrap().expect("the last chunk should complete the message");
+        assert_eq!(completed.chunks.len(), chunks.len() + 1);
+        assert_original(GelfMessageWrapper::Chunked(completed).get_payload().unwrap());
+    }
+
+    #[test]
+    fn invalid_chunk_headers_are_rejected() {
+        let chunk = |sequence_number: u8, total_chunks: u8| {
+            let mut data = vec![0x1e,0x0f,0,0,0,0,0,0,0,1,sequence_number,total_chunks];
+            data.extend_from_slice(b"payload");
+            let (id, sequence_number, total_chunks) = parse_chunk_info(&data);
+            GelfPacket::new_chunked(data,id,sequence_number,total_chunks,"192.168.1.10:5000".parse().unwrap())
+        };
+        assert!(chunk(0,1).validate_chunk().is_ok());
+        assert!(chunk(127,128).validate_chunk().is_ok());
+        assert!(matches!(chunk(0,0).validate_chunk(), Err(ChunkError::NoChunks)));
+        assert!(matches!(chunk(0,129).validate_chunk(), Err(ChunkError::TooManyChunks(129))));
+        assert!(matches!(chunk(3,3).validate_chunk(), Err(ChunkError::SequenceOutOfRange(3,3))));
+        let short = GelfPacket::new_chunked(vec![0x1e,0x0f,0,0,0,0],0,0,0,"192.168.1.10:5000".parse().unwrap());
+        assert!(matches!(short.validate_chunk(), Err(ChunkError::TruncatedHeader)));
+        let mut message = GelfChunkedMessage::new(chunk(0,3));
+        assert!(matches!(message.add_chunk(chunk(1,4)), Err(ChunkError::InconsistentTotal(4,3))));
+        assert!(!message.is_complete());
+    }
+
+    #[test]
+    fn messages_without_line_round_trip() {
+        let json = r#"{"version":"1.1","host":"example","short_message":"hello","_user":"alice"}"#;
+        let message : GelfMessage = serde_json::from_str(json).expect("line is optional");
+        assert_eq!(message.line, None);
+        let serialized = serde_json::to_string(&message).unwrap();
+        assert!(!serialized.contains("line"), "{serialized}");
+        let again : GelfMessage = serde_json::from_str(&serialized).expect("a serialized message should parse again");
+        assert_eq!(again.host, "example");
+        assert_eq!(again.field("_user").as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn messages_without_line_can_be_sent_to_stream_backends() {
+        // this is what the dead-letter output builds for tcp and http backends
+        let message = GelfMessage {
+            version: "1.1".into(), host: "example".into(), short_message: "hello".into(), full_message: None,
+            timestamp: None, level: Some(4), facility: None, file: None, line: None, additional_fields: HashMap::new()
+        };
+        let mut packet = GelfMessageWrapper::Simple(GelfPacket::new_simple(vec![],"127.0.0.1:1234".parse().unwrap()));
+        packet.set_payload(message,&crate::Configuration::default()).unwrap();
+        let frame = packet.to_stream_frame().expect("a message without line should encode as a stream frame");
+        assert_eq!(frame.last(), Some(&0));
+    }
+
+    #[test]
+    fn line_can_be_a_number_a_string_or_null() {
+        for (line,expected) in [("42",Some("42")),(r#""42""#,Some("42")),("null",None)] {
+            let json = format!(r#"{{"version":"1.1","host":"example","short_message":"hello","line":{line}}}"#);
+            let message : GelfMessage = serde_json::from_str(&json).unwrap();
+            assert_eq!(message.line.as_deref(), expected);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::{net::{SocketAddr, TcpStream}, sync::{Arc, Weak}, time::Duration};
+
+use crate::{configuration::{HealthCheck, HealthCheckKind}, pool::BackendServer};
+
+// every backend with a health check gets its own thread, so a slow or hanging backend can not delay the checks of the others.
+// the thread stops once the backend has been removed from its pool.
+pub fn watch(backend: &Arc<BackendServer>) {
+    if backend.health_check.is_some() {
+        let backend = Arc::downgrade(backend);
+        std::thread::spawn(move||checker(backend));
+    }
+}
+
+fn checker(backend: Weak<BackendServer>) {
+
+    let Some(health_check) = backend.upgrade().and_then(|x|x.health_check.clone()) else { return };
+    let timeout = Duration::from_secs(health_check.timeout_seconds);
+    let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+
+    // consecutive results that disagree with the current state
+    let mut streak = 0;
+
+    loop {
+
+        let Some(backend) = backend.upgrade() else {
+            return
+        };
+        let addr = SocketAddr::new(backend.addr.ip(),health_check.port.unwrap_or(backend.addr.port()));
+        let result = check(&agent,&health_check,addr,timeout);
+        let healthy = backend.is_healthy();
+
+        if result.is_ok() == healthy {
+            streak = 0;
+        } else {
+            streak += 1;
+            if let Err(e) = &result {
+                log::debug!("health check of {} failed: {e:#}",backend.addr);
+            }
+            let threshold = if healthy { health_check.fall } else { health_check.rise };
+            if streak >= threshold {
+                streak = 0;
+                *backend.healthy.write().expect("should always be possible to update backend health") = !healthy;
+                match result {
+                    Ok(()) => {
+                        log::info!("backend {} is healthy again",backend.addr);
+                        backend.mark_recovered();
+                    },
+                    Err(e) => log::warn!("backend {} is unhealthy and will not get any traffic until it recovers: {e:#}",backend.addr)
+                }
+            }
+        }
+
+        drop(backend);
+        std::thread::sleep(Duration::from_secs(health_check.interval_seconds));
+    }
+}
+
+fn check(agent: &ureq::Agent, health_check: &HealthCheck, addr: SocketAddr, timeout: Duration) -> anyhow::Result<()> {
+    match health_check.kind {
+        HealthCheckKind::Tcp => {
+            TcpStream::connect_timeout(&addr,timeout)?;
+            Ok(())
+        },
+        HealthCheckKind::Http => {
+            let body = agent.get(&format!("http://{addr}{}",health_check.path)).call()?.into_string()?;
+            if body.trim().eq_ignore_ascii_case("ALIVE") {
+                Ok(())
+            } else {
+                anyhow::bail!("expected ALIVE but the backend answered {:?}",body.trim())
+            }
+        }
+    }
+}
@@ -4,9 +4,22 @@ mod gelf;
 mod web;
 mod configuration;
 mod state;
-use std::{collections::HashMap, net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket}, str::FromStr, sync::RwLock, time::Duration};
+mod stream;
+mod pool;
+mod mirror;
+mod dead_letter;
+mod rules;
+mod webhook;
+mod archive;
+mod health;
+mod circuit_breaker;
+mod routing;
+mod discovery;
+mod dns;
+mod graylog;
+mod reassembly;
+use std::{collections::HashMap, net::{IpAddr, SocketAddr, UdpSocket}, str::FromStr, sync::RwLock, time::Duration};
 use configuration::*;
-use anyhow::Context;
 use gelf::*;
 use state::*;
 
@@ -81,57 +94,122 @@ async fn main() {
 
     let socket = UdpSocket::bind(&listen_addr).expect("Failed to bind to address");
 
-    let backend_servers: Vec<SocketAddr> = config.backends.iter().map(|x| {
-        format!("{}:{}", x.ip, x.port).to_socket_addrs().context(format!("to_socket_addr for {:?}",x))?.next()
-            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No IP addresses found."))
-            .context(format!("configuring backend: {:?}",x))
-    }).collect::<Result<Vec<_>, _>>().unwrap();
-    
+    let config = std::sync::Arc::new(config);
+    // chunks that we collect and pins of chunks that we pass through both last this long
+    let reassembly_timeout = Duration::from_secs(config.reassembly_timeout_seconds.max(1));
 
-    for b in &backend_servers {
-        if config.transparent {
-            if b.is_ipv6() && listen_addr.is_ipv4() {
-                panic!("invalid configuration! you cannot use ipv4 backends while listening on ipv6 when you use transparent mode.")
-            } else if b.is_ipv6() && listen_addr.is_ipv6() {
-                panic!("invalid configuration! you cannot use ipv6 backends while listening on ipv4 when you use transparent mode.")
-            }
+    // a hash ring without points has no backend for any key
+    let balancings = std::iter::once(&config.balancing).chain(config.pools.iter().map(|x|&x.balancing)).chain(config.mirror.iter().map(|x|&x.balancing));
+    for balancing in balancings {
+        if balancing.virtual_nodes == 0 {
+            panic!("invalid configuration! virtual_nodes must be at least 1.")
+        }
+    }
+
+    // the top level backends form the pool called "default", next to the named pools
+    let mut routed_pools = vec![std::sync::Arc::new(pool::Pool::new("default",discovery::resolve(&config.backends,&config).unwrap(),config.chunk_affinity_by_source,reassembly_timeout,config.balancing.clone()))];
+    for named_pool in &config.pools {
+        if routed_pools.iter().any(|x|x.name == named_pool.name) {
+            panic!("invalid configuration! there is more than one pool named {:?}.",named_pool.name)
+        }
+        routed_pools.push(std::sync::Arc::new(pool::Pool::new(&named_pool.name,discovery::resolve(&named_pool.backends,&config).unwrap(),config.chunk_affinity_by_source,reassembly_timeout,named_pool.balancing.clone())));
+    }
+    let mirror_backend_servers = config.mirror.as_ref().map(|x|discovery::resolve(&x.backends,&config).unwrap()).unwrap_or_default();
+    let mirror_pool = std::sync::Arc::new(pool::Pool::new("mirror",mirror_backend_servers,config.chunk_affinity_by_source,reassembly_timeout,config.mirror.as_ref().map(|x|x.balancing.clone()).unwrap_or_default()));
+    let mut pools = routed_pools.clone();
+    if config.mirror.is_some() {
+        pools.push(mirror_pool.clone());
+    }
+
+    // names are resolved again periodically, backend files are watched and graylog clusters are asked for their nodes,
+    // so that changes reach the pools without a restart
+    let mut discoverers = vec![discovery::discover(config.clone(),routed_pools[0].clone(),config.backends.clone(),config.backends_file.clone(),config.graylog_discovery.clone()).unwrap()];
+    for (named_pool,pool) in config.pools.iter().zip(&routed_pools[1..]) {
+        discoverers.push(discovery::discover(config.clone(),pool.clone(),named_pool.backends.clone(),named_pool.backends_file.clone(),named_pool.graylog_discovery.clone()).unwrap());
+    }
+    if let Some(mirror) = &config.mirror {
+        discoverers.push(discovery::discover(config.clone(),mirror_pool.clone(),mirror.backends.clone(),None,None).unwrap());
+    }
+
+    // the dead-letter backend is not balanced, so it has no use for health checks or a circuit breaker
+    let dead_letter_backend_server = config.dead_letter.as_ref().and_then(|x|x.backend.as_ref())
+        .map(|x|discovery::resolve(std::slice::from_ref(x),&config).unwrap().remove(0).with_health_check(None).with_circuit_breaker(None));
+
+    let has_stream_backends = pools.iter().flat_map(|x|x.backends()).any(|x|x.protocol.is_stream());
+    if config.oversized_message_policy == OversizedMessagePolicy::Stream && !routed_pools.iter().flat_map(|x|x.backends()).any(|x|x.protocol.is_stream()) {
+        panic!("invalid configuration! the 'stream' oversized_message_policy requires at least one backend with protocol = \"tcp\" or \"http\".")
+    }
+
+    for b in pools.iter().flat_map(|x|x.backends()) {
+        if let Err(e) = discovery::check_backend(&b,&config,true) {
+            panic!("invalid configuration! {e}")
         }
     }
 
     let (sender, receiver) = 
         std::sync::mpsc::channel::<GelfMessageWrapper>();
     
-    let config = std::sync::Arc::new(config);
+    let router = routing::Router::new(config.clone(),&routed_pools).expect("invalid configuration! routes and default_pool must refer to existing pools or splits");
+    let needs_message_for_balancing = routed_pools.iter().any(|x|x.balancing().needs_message()) || config.splits.iter().any(|x|x.needs_message());
     let state = std::sync::Arc::new(crate::State { 
         nr_of_handled_udp_packets: RwLock::new(0),  
         nr_of_forwarded_messages: RwLock::new(0), 
-        chunked_messages: std::sync::Mutex::new(HashMap::new()),
-        otf_massage_required:  config.transparent || config.attach_source_info || config.blank_fields.len() > 0 || config.strip_fields.len() > 0
+        nr_of_dropped_messages: RwLock::new(HashMap::new()),
+        nr_of_dropped_chunks: RwLock::new(HashMap::new()),
+        nr_of_mirrored_messages: RwLock::new(0),
+        nr_of_mirror_failures: RwLock::new(0),
+        nr_of_webhook_calls: RwLock::new(0),
+        nr_of_failed_webhook_calls: RwLock::new(0),
+        nr_of_rate_limited_webhook_calls: RwLock::new(0),
+        nr_of_archived_messages: RwLock::new(0),
+        nr_of_archive_failures: RwLock::new(0),
+        pools,
+        router,
+        chunked_messages: reassembly::Reassembly::new(reassembly_timeout),
+        // tcp and http backends can only receive complete messages and webhooks/archives/routes/hash keys need to look at them, so those also require us to collect all chunks first
+        otf_massage_required:  config.transparent || config.attach_source_info || !config.blank_fields.is_empty() || !config.strip_fields.is_empty() || has_stream_backends || !config.webhooks.is_empty() || config.archive.is_some() || !config.routes.is_empty() || needs_message_for_balancing
     });
     
+    // backends that are discovered later can only be tcp or http ones if we are reassembling messages already
+    for discoverer in discoverers.into_iter().flatten() {
+        discoverer.spawn(state.otf_massage_required);
+    }
+
     let balancer_state = state.clone();
     let balancer_config = config.clone();
     let cleanup_state = state.clone();
+    let cleanup_config = config.clone();
+
+    // the outputs get their own threads so that they can never slow down the primary backends
+    let mut outputs = balancer::Outputs::default();
+    if config.mirror.is_some() {
+        outputs.mirror = Some(mirror::spawn(state.clone(),config.clone(),mirror_pool.clone()));
+    }
+    if config.dead_letter.is_some() {
+        outputs.dead_letters = Some(dead_letter::spawn(config.clone(),dead_letter_backend_server));
+    }
+    if !config.webhooks.is_empty() {
+        outputs.webhooks = Some(webhook::spawn(state.clone(),config.clone()));
+    }
+    if config.archive.is_some() {
+        outputs.archive = Some(archive::spawn(state.clone(),config.clone()));
+    }
 
     // init balancer thread
-    std::thread::spawn(move||balancer::balancer(balancer_state.clone(),balancer_config.clone(),receiver,backend_servers));
+    std::thread::spawn(move||balancer::balancer(balancer_state.clone(),balancer_config.clone(),receiver,outputs));
     
     // perform periodic cleanup in separate thread - only needed if we store chunks due to needing to modify messages on the fly
     if state.otf_massage_required {
         std::thread::spawn(move|| {
             let cleanup_state = cleanup_state;
+            let timeout = cleanup_config.reassembly_timeout_seconds.max(1);
+            let sweep_interval = Duration::from_secs(cleanup_config.reassembly_sweep_seconds.max(1));
             loop {            
-                std::thread::sleep(Duration::from_secs(10));
+                std::thread::sleep(sweep_interval);
                 
-                let mut nr_removed = 0;
-                {
-                    let mut guard = cleanup_state.chunked_messages.lock().unwrap();    
-                    let before = guard.len();
-                    guard.retain(|_k,v|v.age_in_seconds() < 10);
-                    nr_removed += before.abs_diff(guard.len());
-                }
+                let nr_removed = cleanup_state.chunked_messages.expire();
                 if nr_removed > 0 {
-                    log::debug!("Dropped {nr_removed} messages due to not receiving all chunks within 10 seconds!");
+                    log::debug!("Dropped {nr_removed} messages due to not receiving all chunks within {timeout} seconds!");
                 } else {
                     log::trace!("All is good, we do not have any old chunks :D")
                 }
@@ -201,6 +279,11 @@ async fn main() {
             client_addr,
         );
         let wrapped = if gelf_packet.is_chunked() {
+            if let Err(e) = gelf_packet.validate_chunk() {
+                log::debug!("dropping a chunk from {client_addr}: {e}");
+                state.count_dropped_chunk(e.reason());
+                continue
+            }
             GelfMessageWrapper::Chunked(GelfChunkedMessage::new(gelf_packet))
         } else {
             GelfMessageWrapper::Simple(gelf_packet)
@@ -210,4 +293,3 @@ async fn main() {
             .expect("Failed to send packet to worker");
     }
 }
-
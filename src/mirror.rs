@@ -0,0 +1,92 @@
+use std::sync::{mpsc::{SyncSender, TrySendError}, Arc};
+
+use crate::{balancer::{is_start_of_message, Forwarder}, pool::{chunk_affinity_hash, Pool}, GelfMessageWrapper};
+
+// the balancer side of the mirror: decides which messages to copy and hands them over without ever waiting
+pub struct MirrorSender {
+    state: Arc<crate::State>,
+    sender: SyncSender<GelfMessageWrapper>,
+    percentage: u64,
+    credit: u64
+}
+
+impl MirrorSender {
+
+    pub fn offer(&mut self, packet: &GelfMessageWrapper) {
+        if !self.is_sampled(packet) {
+            return
+        }
+        match self.sender.try_send(packet.clone()) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => {
+                log::trace!("mirror queue is full, not mirroring a message");
+                self.state.nr_of_mirror_failures.write().map(|mut x| *x += 1)
+                    .expect("should always be possible to increment mirror failure count");
+            },
+            Err(TrySendError::Disconnected(_)) => log::warn!("the mirror thread is gone, not mirroring a message")
+        }
+    }
+
+    fn is_sampled(&mut self, packet: &GelfMessageWrapper) -> bool {
+        if self.percentage >= 100 {
+            return true
+        }
+        // all chunks of a message must get the same answer, so for those we let the id decide. ids are often sequential
+        // or time based, so they are mixed the same way as for chunk affinity before they are sampled.
+        if let Some(id) = packet.pkg_id() {
+            return chunk_affinity_hash(id,Some(packet.pkg_src().ip())) % 100 < self.percentage
+        }
+        self.credit += self.percentage;
+        if self.credit >= 100 {
+            self.credit -= 100;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub fn spawn(state: Arc<crate::State>, config: Arc<crate::Configuration>, pool: Arc<Pool>) -> MirrorSender {
+    let mirror_config = config.mirror.as_ref().expect("spawning a mirror requires a mirror configuration");
+    let (sender, receiver) = std::sync::mpsc::sync_channel::<GelfMessageWrapper>(mirror_config.queue_size);
+    let percentage = mirror_config.percentage.min(100) as u64;
+    let mirror_state = state.clone();
+    std::thread::spawn(move||mirror(mirror_state,config,receiver,pool));
+    MirrorSender { state, sender, percentage, credit: 0 }
+}
+
+fn mirror(state: Arc<crate::State>, config: Arc<crate::Configuration>, receiver: std::sync::mpsc::Receiver<GelfMessageWrapper>, pool: Arc<Pool>) {
+
+    let mut forwarder = Forwarder::new();
+
+    while let Ok(packet) = receiver.recv() {
+
+        let backend = if matches!(packet,GelfMessageWrapper::Stream(_)) {
+            pool.select_stream()
+        } else if pool.balancing().needs_message() {
+            // the message was already encoded again for the primary backends, so we have to parse it once more
+            let message = packet.get_payload().ok();
+            pool.select(&packet,message.as_ref())
+        } else {
+            pool.select(&packet,None)
+        };
+
+        let result = match backend {
+            Some(backend) => forwarder.forward(&config,&packet,&backend).map(|()|backend),
+            None => Err(anyhow::anyhow!("there is no backend in the mirror pool that can take this message"))
+        };
+
+        match result {
+            Ok(backend) => if is_start_of_message(&packet) {
+                state.nr_of_mirrored_messages.write().map(|mut x| *x += 1)
+                    .expect("should always be possible to increment mirror count");
+                backend.count_forwarded();
+            },
+            Err(e) => {
+                log::debug!("failed to mirror a message: {e}");
+                state.nr_of_mirror_failures.write().map(|mut x| *x += 1)
+                    .expect("should always be possible to increment mirror failure count");
+            }
+        }
+    }
+}
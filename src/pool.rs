@@ -0,0 +1,904 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket}, sync::{mpsc::{SyncSender, TrySendError}, Arc, Mutex, OnceLock, RwLock}, time::{Duration, Instant}};
+use anyhow::Context;
+
+use crate::{circuit_breaker::Circuit, configuration::{BackendProtocol, Balancing, BalancingStrategy, CircuitBreaker, HealthCheck}, gelf::sequence_bit, GelfMessage, GelfMessageWrapper};
+
+#[derive(Debug)]
+pub struct BackendServer {
+    pub addr: SocketAddr,
+    pub protocol: BackendProtocol,
+    pub weight: u32,
+    /// the backends with the lowest priority number get all traffic, the others are standby
+    pub priority: u32,
+    pub health_check: Option<HealthCheck>,
+    /// drained in the configuration, it stays drained until the configuration changes
+    pub drain: bool,
+    /// drained at runtime through the web api
+    draining: RwLock<bool>,
+    /// when the backend last came back, it ramps up from here if the pool has a slow start
+    recovered_at: RwLock<Option<Instant>>,
+    /// backends start out healthy, only the health checker changes this
+    pub healthy: RwLock<bool>,
+    pub circuit: Mutex<Circuit>,
+    pub nr_of_forwarded_messages: RwLock<u64>,
+    pub nr_of_send_failures: RwLock<u64>,
+    /// tcp and http backends are sent to by their own thread, this is how messages get there
+    queue: OnceLock<SyncSender<Vec<u8>>>,
+    /// messages that are queued for the backend or being sent to it
+    pending: RwLock<u64>,
+    /// moving average of how long a send to the backend takes, only known for tcp and http backends
+    latency: RwLock<Option<Duration>>,
+    /// the connected socket that udp messages are sent with, it goes away with the backend
+    udp_socket: OnceLock<UdpSocket>
+}
+
+impl BackendServer {
+    pub fn new(addr: SocketAddr, protocol: BackendProtocol, weight: u32) -> Self {
+        Self {
+            addr, protocol, weight,
+            priority: 0,
+            health_check: None,
+            drain: false,
+            draining: RwLock::new(false),
+            recovered_at: RwLock::new(None),
+            healthy: RwLock::new(true),
+            circuit: Mutex::new(Circuit::new(None)),
+            nr_of_forwarded_messages: RwLock::new(0),
+            nr_of_send_failures: RwLock::new(0),
+            queue: OnceLock::new(),
+            pending: RwLock::new(0),
+            latency: RwLock::new(None),
+            udp_socket: OnceLock::new()
+        }
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Option<CircuitBreaker>) -> Self {
+        self.circuit = Mutex::new(Circuit::new(circuit_breaker));
+        self
+    }
+
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_health_check(mut self, health_check: Option<HealthCheck>) -> Self {
+        self.health_check = health_check;
+        self
+    }
+
+    pub fn with_drain(mut self, drain: bool) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    // takes over what we learned about the backend, for when only its settings change
+    fn with_state_of(self, other: &BackendServer) -> Self {
+        *self.healthy.write().unwrap() = other.is_healthy();
+        *self.draining.write().unwrap() = *other.draining.read().unwrap();
+        *self.recovered_at.write().unwrap() = *other.recovered_at.read().unwrap();
+        *self.circuit.lock().unwrap() = other.circuit.lock().unwrap().clone();
+        *self.nr_of_forwarded_messages.write().unwrap() = *other.nr_of_forwarded_messages.read().unwrap();
+        *self.nr_of_send_failures.write().unwrap() = *other.nr_of_send_failures.read().unwrap();
+        *self.latency.write().unwrap() = *other.latency.read().unwrap();
+        self
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        *self.healthy.read().expect("should always be possible to read backend health")
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.drain || *self.draining.read().expect("should always be possible to read backend drain state")
+    }
+
+    /// Drains the backend or takes it back in to service, returns false if it already was in that state.
+    pub fn set_draining(&self, draining: bool) -> bool {
+        let was_draining = self.is_draining();
+        *self.draining.write().expect("should always be possible to update backend drain state") = draining;
+        if was_draining == self.is_draining() {
+            return false
+        }
+        if draining {
+            log::info!("draining backend {}, it gets no new messages",self.addr);
+        } else {
+            log::info!("backend {} is no longer draining",self.addr);
+            self.mark_recovered();
+        }
+        true
+    }
+
+    /// Starts the slow start of the backend, for when it can take traffic again after it could not.
+    pub fn mark_recovered(&self) {
+        *self.recovered_at.write().expect("should always be possible to update backend recovery time") = Some(Instant::now());
+    }
+
+    fn allows_traffic(&self) -> bool {
+        self.weight > 0 && !self.is_draining() && self.is_healthy() && self.circuit.lock().unwrap().allows_traffic()
+    }
+
+    /// Tells the circuit breaker that a message is on its way to the backend, see Circuit::start_probe.
+    pub fn start_probe(&self) {
+        self.circuit.lock().unwrap().start_probe();
+    }
+
+    /// Feeds the outcome of a send to this backend to its circuit breaker.
+    pub fn record_send(&self, result: &anyhow::Result<()>) {
+        let mut circuit = self.circuit.lock().unwrap();
+        match result {
+            Ok(()) => if circuit.record_success() {
+                log::info!("the circuit of backend {} is closed again",self.addr);
+                self.mark_recovered();
+            },
+            Err(e) => {
+                self.nr_of_send_failures.write().map(|mut x| *x += 1)
+                    .expect("should always be possible to increment backend failure count");
+                if circuit.record_failure() {
+                    log::warn!("opened the circuit of backend {} after repeated failures, the last one was: {e:#}",self.addr);
+                }
+            }
+        }
+    }
+
+    /// Hands the sending thread of the backend its queue, returns false if it already has one.
+    pub fn set_queue(&self, sender: SyncSender<Vec<u8>>) -> bool {
+        self.queue.set(sender).is_ok()
+    }
+
+    /// True if the backend has a sending thread that frames can be queued for.
+    pub fn has_queue(&self) -> bool {
+        self.queue.get().is_some()
+    }
+
+    /// Queues a frame for the sending thread of the backend.
+    pub fn enqueue(&self, frame: Vec<u8>) -> anyhow::Result<()> {
+        let Some(queue) = self.queue.get() else {
+            anyhow::bail!("backend {} has no sending thread",self.addr)
+        };
+        // counted before sending, as the sending thread might be done with it before try_send returns
+        *self.pending.write().expect("should always be possible to update pending count") += 1;
+        let result = match queue.try_send(frame) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(_)) => Err(anyhow::anyhow!("the queue of backend {} is full",self.addr)),
+            Err(TrySendError::Disconnected(_)) => Err(anyhow::anyhow!("the sending thread of backend {} is gone",self.addr))
+        };
+        *self.pending.write().expect("should always be possible to update pending count") -= 1;
+        result
+    }
+
+    /// Called by the sending thread once it is done with a queued frame.
+    pub fn record_sent(&self, elapsed: Duration, result: &anyhow::Result<()>) {
+        self.pending.write().map(|mut x| *x = x.saturating_sub(1)).expect("should always be possible to update pending count");
+        if result.is_ok() {
+            let mut latency = self.latency.write().expect("should always be possible to update backend latency");
+            *latency = Some(match *latency {
+                Some(x) => x.mul_f64(1.0 - LATENCY_SMOOTHING) + elapsed.mul_f64(LATENCY_SMOOTHING),
+                None => elapsed
+            });
+        }
+        self.record_send(result);
+    }
+
+    /// The socket for sending to a udp backend, connected on first use. the kernel only reports icmp port unreachable on
+    /// connected sockets, as an error on the next send, which is how we notice that nothing is listening on a backend.
+    pub fn udp_socket(&self) -> anyhow::Result<&UdpSocket> {
+        if let Some(socket) = self.udp_socket.get() {
+            return Ok(socket)
+        }
+        let socket = if self.addr.is_ipv4() {
+            UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(std::net::Ipv4Addr::UNSPECIFIED,0)))?
+        } else {
+            UdpSocket::bind(SocketAddr::V6(SocketAddrV6::new(std::net::Ipv6Addr::UNSPECIFIED,0, 0, 0)))?
+        };
+        socket.connect(self.addr).with_context(|| format!("failed to connect udp socket to {}",self.addr))?;
+        // another thread may have been first, then its socket is used and this one is closed
+        Ok(self.udp_socket.get_or_init(|| socket))
+    }
+
+    pub fn pending(&self) -> u64 {
+        *self.pending.read().expect("should always be possible to read pending count")
+    }
+
+    pub fn latency(&self) -> Option<Duration> {
+        *self.latency.read().expect("should always be possible to read backend latency")
+    }
+
+    // how long a new message would take to get through, relative to the weight of the backend
+    fn expected_wait(&self, weight: u64) -> f64 {
+        let latency = self.latency().unwrap_or(DEFAULT_LATENCY).as_secs_f64();
+        (self.pending() + 1) as f64 * latency / weight.max(1) as f64
+    }
+
+    pub fn count_forwarded(&self) {
+        self.nr_of_forwarded_messages.write().map(|mut x| *x += 1)
+            .expect("should always be possible to increment backend fwd count");
+    }
+}
+
+// smooth weighted round-robin state, one entry per backend in the same order as the backends
+#[derive(Debug, Default)]
+struct RoundRobin {
+    current_weights: Vec<i64>,
+    next_stream_index: usize,
+    /// where the least loaded strategies start looking, so that equally loaded backends take turns
+    next_least_loaded: usize
+}
+
+// how much a new latency measurement counts in the moving average
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+// what we assume for backends without measurements, udp backends never have any
+const DEFAULT_LATENCY: Duration = Duration::from_millis(1);
+
+// backend weights are multiplied by this for selection, so that a slow start can ramp them up in small steps
+const WEIGHT_SCALE: u64 = 1000;
+
+// when we pass chunks through one by one, every chunk of a message has to go to the backend that got the first one.
+// hashing alone does not guarantee that once the backends change (discovery, health), so in-flight messages are pinned.
+#[derive(Debug)]
+struct Pins {
+    pinned: HashMap<u64,Pin>,
+    /// chunks of a message arrive within the reassembly timeout, older pins belong to messages that will never complete
+    lifetime: Duration,
+    last_sweep: Instant
+}
+
+#[derive(Debug)]
+struct Pin {
+    backend: Arc<BackendServer>,
+    total_chunks: u8,
+    /// one bit per sequence number, duplicates go to the same backend but do not count towards the total
+    seen: u128,
+    created: Instant
+}
+
+impl Pins {
+
+    fn take(&mut self, key: u64, sequence_number: u8) -> Option<Arc<BackendServer>> {
+        let pin = self.pinned.get_mut(&key)?;
+        let backend = pin.backend.clone();
+        pin.seen |= sequence_bit(sequence_number);
+        if pin.seen.count_ones() >= pin.total_chunks as u32 {
+            self.pinned.remove(&key);
+        }
+        Some(backend)
+    }
+
+    fn pin(&mut self, key: u64, backend: Arc<BackendServer>, total_chunks: u8, sequence_number: u8) {
+        if self.last_sweep.elapsed() >= self.lifetime {
+            let lifetime = self.lifetime;
+            self.pinned.retain(|_,x|x.created.elapsed() < lifetime);
+            self.last_sweep = Instant::now();
+        }
+        self.pinned.insert(key, Pin { backend, total_chunks, seen: sequence_bit(sequence_number), created: Instant::now() });
+    }
+}
+
+// a named set of backends with its own balancing state, so that several pools can be balanced independently.
+// pools are shared between the balancer and whatever reports on them, so all mutable parts are behind locks.
+#[derive(Debug)]
+pub struct Pool {
+    pub name: String,
+    members: RwLock<Members>,
+    round_robin: Mutex<RoundRobin>,
+    /// include the sender ip when picking the backend for a chunked message, so that senders that happen to use
+    /// the same message ids are still spread over the backends
+    chunk_affinity_by_source: bool,
+    balancing: Balancing,
+    /// the priority group that got the last message, to notice fail over and fail back
+    active_priority: Mutex<Option<u32>>,
+    pins: Mutex<Pins>
+}
+
+// the backends of a pool can change at runtime (dns, discovery), the ring always belongs to the backends next to it
+#[derive(Debug)]
+struct Members {
+    backends: Vec<Arc<BackendServer>>,
+    /// sorted (hash, backend index) points, only used by the consistent hash strategy
+    ring: Vec<(u64,usize)>
+}
+
+impl Pool {
+
+    pub fn new(name: &str, backends: Vec<BackendServer>, chunk_affinity_by_source: bool, pin_lifetime: Duration, balancing: Balancing) -> Self {
+        let backends : Vec<Arc<BackendServer>> = backends.into_iter().map(Arc::new).collect();
+        for backend in &backends {
+            watch(backend);
+        }
+        Self {
+            name: name.to_string(),
+            round_robin: Mutex::new(RoundRobin { current_weights: vec![0; backends.len()], ..Default::default() }),
+            members: RwLock::new(Members { ring: build_ring(&backends, balancing.virtual_nodes), backends }),
+            chunk_affinity_by_source,
+            balancing,
+            active_priority: Mutex::new(None),
+            pins: Mutex::new(Pins { pinned: HashMap::new(), lifetime: pin_lifetime, last_sweep: Instant::now() })
+        }
+    }
+
+    pub fn balancing(&self) -> &Balancing {
+        &self.balancing
+    }
+
+    pub fn backends(&self) -> Vec<Arc<BackendServer>> {
+        self.members.read().unwrap().backends.clone()
+    }
+
+    /// Replaces the backends of the pool. backends that stay (same address and protocol) keep their counters, health and circuit.
+    /// nothing happens if the backends are the same as before, so this can be called whenever something might have changed.
+    pub fn set_backends(&self, backends: Vec<BackendServer>) {
+        let mut members = self.members.write().unwrap();
+        let unchanged = members.backends.len() == backends.len() && backends.iter().all(|new| members.backends.iter()
+            .any(|x|x.addr == new.addr && x.protocol == new.protocol && x.weight == new.weight && x.priority == new.priority && x.drain == new.drain));
+        if unchanged {
+            return
+        }
+        let backends : Vec<Arc<BackendServer>> = backends.into_iter().map(|new| {
+            match members.backends.iter().find(|x|x.addr == new.addr && x.protocol == new.protocol) {
+                Some(existing) if existing.weight == new.weight && existing.priority == new.priority && existing.drain == new.drain => existing.clone(),
+                Some(existing) => {
+                    let new = Arc::new(new.with_state_of(existing));
+                    match (existing.is_draining(), new.is_draining()) {
+                        (false, true) => log::info!("draining backend {}, it gets no new messages",new.addr),
+                        (true, false) => {
+                            log::info!("backend {} is no longer draining",new.addr);
+                            new.mark_recovered();
+                        },
+                        _ => {}
+                    }
+                    watch(&new);
+                    new
+                },
+                None => {
+                    log::info!("adding backend {} to pool {}",new.addr,self.name);
+                    new.mark_recovered();
+                    let new = Arc::new(new);
+                    watch(&new);
+                    new
+                }
+            }
+        }).collect();
+        for removed in members.backends.iter().filter(|x|!backends.iter().any(|b|b.addr == x.addr && b.protocol == x.protocol)) {
+            log::info!("removing backend {} from pool {}",removed.addr,self.name);
+        }
+        members.ring = build_ring(&backends, self.balancing.virtual_nodes);
+        members.backends = backends;
+        let mut round_robin = self.round_robin.lock().unwrap();
+        round_robin.current_weights = vec![0; members.backends.len()];
+    }
+
+    /// The share of the traffic of its priority group that a backend should get according to its weight, 0.0 - 1.0.
+    pub fn configured_share(&self, backend: &BackendServer) -> f64 {
+        let members = self.members.read().unwrap();
+        match members.backends.iter().filter(|x|x.priority == backend.priority).map(|x|x.weight as u64).sum::<u64>() {
+            0 => 0.0,
+            total => backend.weight as f64 / total as f64
+        }
+    }
+
+    // traffic goes to the first priority group that has enough backends that can take it. if none has,
+    // the first group that has any is the best we can do.
+    fn active_priority(&self, members: &Members) -> Option<u32> {
+        let mut available : Vec<(u32,usize)> = vec![];
+        for backend in members.backends.iter().filter(|x|x.allows_traffic()) {
+            match available.iter_mut().find(|(p,_)| *p == backend.priority) {
+                Some((_,count)) => *count += 1,
+                None => available.push((backend.priority,1))
+            }
+        }
+        available.sort_unstable();
+        let active = available.iter().find(|(_,count)| *count >= self.balancing.min_available_backends)
+            .or(available.first())
+            .map(|(p,_)| *p);
+
+        let mut previous = self.active_priority.lock().unwrap();
+        if *previous != active {
+            match (*previous, active) {
+                (Some(from), Some(to)) if to > from => log::warn!("pool {} is failing over from priority {from} to priority {to}",self.name),
+                (Some(from), Some(to)) => log::info!("pool {} is failing back from priority {from} to priority {to}",self.name),
+                (_, None) => log::warn!("pool {} has no backend that can take traffic",self.name),
+                (None, Some(to)) => log::debug!("pool {} is sending traffic to priority {to}",self.name)
+            }
+            *previous = active;
+        }
+        active
+    }
+
+    /// How far a backend is in to its slow start, from 0.0 when it just came back to 1.0 once it gets its full weight.
+    pub fn slow_start_progress(&self, backend: &BackendServer) -> f64 {
+        let recovered_at = *backend.recovered_at.read().expect("should always be possible to read backend recovery time");
+        match recovered_at {
+            Some(x) if self.balancing.slow_start_seconds > 0 => (x.elapsed().as_secs_f64() / self.balancing.slow_start_seconds as f64).min(1.0),
+            _ => 1.0
+        }
+    }
+
+    // the weight that selection uses: unhealthy, draining and standby backends and backends with an open circuit get nothing.
+    // weights are scaled up so that a slow start can hand out a fraction of them. a backend that just came back still gets
+    // a little, otherwise it would get nothing at all if it is the only one.
+    fn selectable_weight(&self, backend: &BackendServer, priority: u32) -> u64 {
+        if backend.priority != priority || !backend.allows_traffic() {
+            return 0
+        }
+        let weight = backend.weight as u64 * WEIGHT_SCALE;
+        ((weight as f64 * self.slow_start_progress(backend)) as u64).max(1)
+    }
+
+    fn total_selectable_weight(&self, members: &Members, priority: u32) -> u64 {
+        members.backends.iter().map(|x|self.selectable_weight(x,priority)).sum()
+    }
+
+    // the message is only available when we are processing messages rather than passing packets through as they are.
+    // chunked messages always go to the same backend for each chunk, everything else is weighted round-robin
+    // unless the strategy says otherwise.
+    pub fn select(&self, packet: &GelfMessageWrapper, message: Option<&GelfMessage>) -> Option<Arc<BackendServer>> {
+        let pin = match packet {
+            GelfMessageWrapper::Chunked(x) if is_lone_chunk(packet) => {
+                // the pin always includes the sender, so that two senders using the same id never share a pin
+                Some((chunk_affinity_hash(x.id,Some(packet.pkg_src().ip())), x.expected_max_chunks as u8, x.chunks[0].sequence_number))
+            },
+            _ => None
+        };
+        if let Some((key,_,sequence_number)) = pin {
+            if let Some(backend) = self.pins.lock().unwrap().take(key,sequence_number) {
+                return Some(backend)
+            }
+        }
+        let backend = self.select_unpinned(packet,message)?;
+        if let Some((key,total_chunks,sequence_number)) = pin {
+            self.pins.lock().unwrap().pin(key,backend.clone(),total_chunks,sequence_number);
+        }
+        Some(backend)
+    }
+
+    fn select_unpinned(&self, packet: &GelfMessageWrapper, message: Option<&GelfMessage>) -> Option<Arc<BackendServer>> {
+        let members = self.members.read().unwrap();
+        let priority = self.active_priority(&members)?;
+        match self.balancing.strategy {
+            BalancingStrategy::RoundRobin => {},
+            BalancingStrategy::ConsistentHash => {
+                let key = if self.balancing.hash_key == "source_ip" {
+                    Some(packet.pkg_src().ip().to_string())
+                } else {
+                    message.and_then(|x|x.field(&self.balancing.hash_key))
+                };
+                // messages without the key are balanced as usual
+                if let Some(key) = key {
+                    return self.select_by_ring(&members,hash_str(&key),priority)
+                }
+            },
+            // all chunks of a message come from the same sender, so this keeps them together as well
+            BalancingStrategy::SourceIp => return self.select_by_hash(&members,mix(ip_bits(packet.pkg_src().ip())),priority),
+            // chunks that are passed through one by one are kept together by the chunk hash below instead.
+            // udp backends never have anything pending, without a backlog anywhere there is no load to compare and the weights decide.
+            BalancingStrategy::LeastOutstanding | BalancingStrategy::PowerOfTwoChoices => if !is_lone_chunk(packet) {
+                let candidates : Vec<_> = members.backends.iter().map(|x|(x,self.selectable_weight(x,priority))).filter(|(_,weight)| *weight > 0).collect();
+                if candidates.iter().any(|(x,_)| x.pending() > 0) {
+                    return self.select_least_loaded(candidates)
+                }
+            }
+        }
+        if packet.is_chunked() {
+            if let Some(pkg_id) = packet.pkg_id() {
+                let source = if self.chunk_affinity_by_source { Some(packet.pkg_src().ip()) } else { None };
+                self.select_by_hash(&members,chunk_affinity_hash(pkg_id,source),priority)
+            } else {
+                log::warn!("We received a chunked message with no id. this should not be possible..");
+                None
+            }
+        } else {
+            self.select_round_robin(&members,priority)
+        }
+    }
+
+    // each backend owns a slice of the hash space that is proportional to its weight
+    fn select_by_hash(&self, members: &Members, hash: u64, priority: u32) -> Option<Arc<BackendServer>> {
+        let mut position = hash_to_index(hash, self.total_selectable_weight(members,priority) as usize) as u64;
+        for backend in &members.backends {
+            let weight = self.selectable_weight(backend,priority);
+            if position < weight {
+                return Some(backend.clone())
+            }
+            position -= weight;
+        }
+        None
+    }
+
+    // the first point on the ring at or after the hash owns it, points of backends that can not take traffic are skipped
+    // so that their keys move to the next backend on the ring while everything else stays where it is.
+    // during a slow start a backend only takes the keys whose hash falls below its progress, so that more and more of its
+    // keys move back to it and none of them moves back and forth.
+    fn select_by_ring(&self, members: &Members, hash: u64, priority: u32) -> Option<Arc<BackendServer>> {
+        let start = members.ring.partition_point(|(point,_)| *point < hash);
+        let position = mix(hash) as f64 / u64::MAX as f64;
+        let candidates = (0..members.ring.len())
+            .map(|i| &members.backends[members.ring[(start + i) % members.ring.len()].1])
+            .filter(|x| self.selectable_weight(x,priority) > 0);
+        let mut fallback = None;
+        for backend in candidates {
+            let progress = self.slow_start_progress(backend);
+            if progress >= 1.0 || position < progress {
+                return Some(backend.clone())
+            }
+            fallback.get_or_insert(backend);
+        }
+        fallback.cloned()
+    }
+
+    // smooth weighted round-robin (as in nginx): spreads the picks of heavy backends out instead of sending bursts to them
+    fn select_round_robin(&self, members: &Members, priority: u32) -> Option<Arc<BackendServer>> {
+        let mut round_robin = self.round_robin.lock().unwrap();
+        let total = self.total_selectable_weight(members,priority) as i64;
+        let mut best : Option<usize> = None;
+        for (i,backend) in members.backends.iter().enumerate() {
+            let weight = self.selectable_weight(backend,priority) as i64;
+            if weight == 0 {
+                continue
+            }
+            round_robin.current_weights[i] += weight;
+            if best.is_none_or(|b| round_robin.current_weights[i] > round_robin.current_weights[b]) {
+                best = Some(i);
+            }
+        }
+        let best = best?;
+        round_robin.current_weights[best] -= total;
+        Some(members.backends[best].clone())
+    }
+
+    // least outstanding: the fewest pending messages for the weight, the faster backend if that is a tie.
+    // power of two choices: two backends picked at random by weight, the one that would get the message through sooner wins.
+    // both look at the candidates from a rotating start, so that equally loaded backends take turns.
+    fn select_least_loaded(&self, candidates: Vec<(&Arc<BackendServer>,u64)>) -> Option<Arc<BackendServer>> {
+        if candidates.is_empty() {
+            return None
+        }
+        let turn = {
+            let mut round_robin = self.round_robin.lock().unwrap();
+            round_robin.next_least_loaded = round_robin.next_least_loaded.wrapping_add(1);
+            round_robin.next_least_loaded
+        };
+        if self.balancing.strategy == BalancingStrategy::PowerOfTwoChoices {
+            // the second choice is drawn from the others, otherwise small pools would often have no choice at all
+            let pick = |hash: u64, skip: Option<usize>| {
+                let others = || candidates.iter().enumerate().filter(move|(i,_)| Some(*i) != skip);
+                let total : u64 = others().map(|(_,(_,weight))| *weight).sum();
+                let mut position = hash_to_index(hash, total as usize) as u64;
+                for (i,(_,weight)) in others() {
+                    if position < *weight {
+                        return i
+                    }
+                    position -= weight;
+                }
+                others().next().map(|(i,_)|i).unwrap_or_default()
+            };
+            let first = pick(mix(turn as u64), None);
+            if candidates.len() == 1 {
+                return Some(candidates[first].0.clone())
+            }
+            let (a, b) = (candidates[first], candidates[pick(mix(!(turn as u64)), Some(first))]);
+            let best = if b.0.expected_wait(b.1) < a.0.expected_wait(a.1) { b.0 } else { a.0 };
+            return Some(best.clone())
+        }
+        (0..candidates.len())
+            .map(|i| candidates[(turn + i) % candidates.len()])
+            .min_by(|(a,a_weight),(b,b_weight)| {
+                let a_load = (a.pending() + 1) as f64 / *a_weight as f64;
+                let b_load = (b.pending() + 1) as f64 / *b_weight as f64;
+                a_load.total_cmp(&b_load).then_with(|| a.latency().unwrap_or(DEFAULT_LATENCY).cmp(&b.latency().unwrap_or(DEFAULT_LATENCY)))
+            })
+            .map(|(x,_)| x.clone())
+    }
+
+    // messages that are too large for udp can only be sent to tcp or http backends, standby ones only if there is no other
+    pub fn select_stream(&self) -> Option<Arc<BackendServer>> {
+        let members = self.members.read().unwrap();
+        let available : Vec<&Arc<BackendServer>> = members.backends.iter().filter(|x|x.protocol.is_stream() && x.allows_traffic()).collect();
+        let priority = available.iter().map(|x|x.priority).min()?;
+        let stream_backends : Vec<&Arc<BackendServer>> = available.into_iter().filter(|x|x.priority == priority).collect();
+        if matches!(self.balancing.strategy, BalancingStrategy::LeastOutstanding | BalancingStrategy::PowerOfTwoChoices) && stream_backends.iter().any(|x|x.pending() > 0) {
+            return self.select_least_loaded(stream_backends.into_iter().map(|x|(x,self.selectable_weight(x,priority))).collect())
+        }
+        let mut round_robin = self.round_robin.lock().unwrap();
+        let index = round_robin.next_stream_index % stream_backends.len();
+        round_robin.next_stream_index = round_robin.next_stream_index.wrapping_add(1);
+        Some(stream_backends[index].clone())
+    }
+}
+
+// the health checker and, for tcp and http backends, the sending thread
+fn watch(backend: &Arc<BackendServer>) {
+    crate::health::watch(backend);
+    crate::stream::spawn(backend);
+}
+
+// a single chunk of a larger message means that we are passing chunks through rather than reassembling them
+fn is_lone_chunk(packet: &GelfMessageWrapper) -> bool {
+    matches!(packet, GelfMessageWrapper::Chunked(x) if x.chunks.len() == 1 && x.expected_max_chunks > 1)
+}
+
+// every backend gets virtual_nodes points per unit of weight, derived from its address so that the ring is the same
+// after a restart and adding or removing a backend only moves the keys next to its own points.
+fn build_ring(backends: &[Arc<BackendServer>], virtual_nodes: u32) -> Vec<(u64,usize)> {
+    let mut ring = vec![];
+    for (index,backend) in backends.iter().enumerate() {
+        for i in 0..virtual_nodes as u64 * backend.weight as u64 {
+            ring.push((hash_str(&format!("{}#{i}",backend.addr)),index));
+        }
+    }
+    ring.sort_unstable();
+    ring
+}
+
+// fnv-1a, mixed afterwards as fnv alone spreads short similar strings poorly
+pub fn hash_str(value: &str) -> u64 {
+    let mut hash : u64 = 0xcbf2_9ce4_8422_2325;
+    for b in value.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    mix(hash)
+}
+
+// for chunked messages we always should select the same backend for each chunk, so we pick it from a hash of the message id.
+// ids are often sequential or time based, so they have to be mixed well before they can be spread over the backends.
+pub fn chunk_affinity_hash(message_id: u64, source: Option<IpAddr>) -> u64 {
+    match source {
+        Some(ip) => mix(message_id ^ mix(ip_bits(ip))),
+        None => mix(message_id)
+    }
+}
+
+fn ip_bits(ip: IpAddr) -> u64 {
+    match ip {
+        IpAddr::V4(x) => u32::from(x) as u64,
+        IpAddr::V6(x) => {
+            let bits = u128::from(x);
+            (bits >> 64) as u64 ^ bits as u64
+        }
+    }
+}
+
+// splitmix64 finalizer: every input bit affects every output bit
+pub fn mix(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+// maps a hash evenly on to 0..len without the bias of a plain modulo
+pub fn hash_to_index(hash: u64, len: usize) -> usize {
+    ((hash as u128 * len as u128) >> 64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::{configuration::{Balancing, BalancingStrategy}, GelfChunkedMessage, GelfPacket};
+
+    fn pool(nr_of_backends: usize, chunk_affinity_by_source: bool) -> Pool {
+        let backends = (0..nr_of_backends)
+            .map(|i| BackendServer::new(SocketAddr::from(([10,0,0,i as u8 + 1],12201)),BackendProtocol::Udp,1))
+            .collect();
+        Pool::new("test",backends,chunk_affinity_by_source,Duration::from_secs(10),Balancing::default())
+    }
+
+    fn chunk(id: u64, sequence_number: u8, total_chunks: u8, source: &str) -> GelfMessageWrapper {
+        let mut data = vec![0x1e,0x0f];
+        data.extend_from_slice(&id.to_be_bytes());
+        data.extend_from_slice(&[sequence_number,total_chunks,b'x']);
+        let packet = GelfPacket::new_chunked(data,id,sequence_number,total_chunks,source.parse().unwrap());
+        GelfMessageWrapper::Chunked(GelfChunkedMessage::new(packet))
+    }
+
+    // every backend should get close to its share, whatever the ids look like
+    fn assert_even(ids: &[u64], name: &str) {
+        for nr_of_backends in 2..=5 {
+            let pool = pool(nr_of_backends,true);
+            let mut counts : HashMap<SocketAddr,usize> = HashMap::new();
+            for id in ids {
+                let backend = pool.select(&chunk(*id,0,2,"192.168.1.10:5000"),None).unwrap();
+                *counts.entry(backend.addr).or_default() += 1;
+            }
+            assert_eq!(counts.len(), nr_of_backends, "{name} ids did not reach every backend");
+            let expected = ids.len() as f64 / nr_of_backends as f64;
+            for (addr,count) in counts {
+                let deviation = (count as f64 - expected).abs() / expected;
+                assert!(deviation < 0.08, "{name} ids over {nr_of_backends} backends: {addr} got {count}, expected about {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_affinity_spreads_sequential_ids() {
+        let ids : Vec<u64> = (0..20_000).collect();
+        assert_even(&ids,"sequential");
+    }
+
+    #[test]
+    fn chunk_affinity_spreads_time_based_ids() {
+        // microsecond timestamps in nanoseconds, like the ids of many gelf libraries
+        let ids : Vec<u64> = (0..20_000u64).map(|i| 1_700_000_000_000_000_000 + i * 1_000).collect();
+        assert_even(&ids,"time based");
+        let ids : Vec<u64> = (0..20_000).map(|_| crate::gelf::generate_message_id()).collect();
+        assert_even(&ids,"generated");
+    }
+
+    #[test]
+    fn chunk_affinity_spreads_by_weight() {
+        let pool = Pool::new("test",vec![
+            BackendServer::new(SocketAddr::from(([10,0,0,1],12201)),BackendProtocol::Udp,1),
+            BackendServer::new(SocketAddr::from(([10,0,0,2],12201)),BackendProtocol::Udp,3)
+        ],true,Duration::from_secs(10),Balancing::default());
+        let heavy = (0..20_000).filter(|id| pool.select(&chunk(*id,0,2,"192.168.1.10:5000"),None).unwrap().weight == 3).count();
+        assert!((heavy as f64 / 20_000.0 - 0.75).abs() < 0.02, "the backend with 3/4 of the weight got {heavy} of 20000");
+    }
+
+    #[test]
+    fn all_chunks_of_a_message_go_to_the_same_backend() {
+        for chunk_affinity_by_source in [false,true] {
+            let pool = pool(4,chunk_affinity_by_source);
+            // a second pool has no pins of its own, so it shows that the hash alone already keeps chunks together
+            let unpinned = self::pool(4,chunk_affinity_by_source);
+            for id in (0..200).map(|i| 1_700_000_000_000_000_000 + i * 1_000) {
+                for source in ["192.168.1.10:5000","192.168.1.11:5000"] {
+                    let first = pool.select(&chunk(id,0,5,source),None).unwrap();
+                    // late chunks can arrive in any order, and from another port of the same sender
+                    for sequence_number in [3,1,4,2] {
+                        let backend = pool.select(&chunk(id,sequence_number,5,source),None).unwrap();
+                        assert_eq!(backend.addr, first.addr, "chunk {sequence_number} of message {id} from {source} moved");
+                        let backend = unpinned.select(&chunk(id,sequence_number,5,&source.replace("5000","5001")),None).unwrap();
+                        assert_eq!(backend.addr, first.addr, "chunk {sequence_number} of message {id} from {source} moved without a pin");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_robin_spreads_the_picks_of_heavy_backends() {
+        let pool = Pool::new("test",vec![
+            BackendServer::new(SocketAddr::from(([10,0,0,1],12201)),BackendProtocol::Udp,5),
+            BackendServer::new(SocketAddr::from(([10,0,0,2],12201)),BackendProtocol::Udp,1),
+            BackendServer::new(SocketAddr::from(([10,0,0,3],12201)),BackendProtocol::Udp,1)
+        ],false,Duration::from_secs(10),Balancing::default());
+        let message = GelfMessageWrapper::Simple(GelfPacket::new_simple(vec![],"192.168.1.10:5000".parse().unwrap()));
+        let names = |addr: SocketAddr| match addr.ip().to_string().as_str() { "10.0.0.1" => 'a', "10.0.0.2" => 'b', _ => 'c' };
+        // the same order comes back every 7 picks
+        for _ in 0..3 {
+            let order : String = (0..7).map(|_| names(pool.select(&message,None).unwrap().addr)).collect();
+            assert_eq!(order, "aabacaa");
+        }
+    }
+
+    #[test]
+    fn removing_a_backend_only_moves_its_own_keys() {
+        let balancing = Balancing { strategy: BalancingStrategy::ConsistentHash, hash_key: "source_ip".into(), ..Balancing::default() };
+        let backends = || (0..4).map(|i| BackendServer::new(SocketAddr::from(([10,0,0,i + 1],12201)),BackendProtocol::Udp,1));
+        let pool = Pool::new("test",backends().collect(),false,Duration::from_secs(10),balancing);
+        let message = |i: u32| GelfMessageWrapper::Simple(GelfPacket::new_simple(vec![],SocketAddr::from((std::net::Ipv4Addr::from(0xc0a8_0000 + i),5000))));
+        let before : Vec<SocketAddr> = (0..2000).map(|i| pool.select(&message(i),None).unwrap().addr).collect();
+
+        let removed = SocketAddr::from(([10,0,0,4],12201));
+        pool.set_backends(backends().filter(|x|x.addr != removed).collect());
+        let mut moved = 0;
+        for (i,old) in before.iter().enumerate() {
+            let new = pool.select(&message(i as u32),None).unwrap().addr;
+            if *old == removed {
+                assert_ne!(new, removed);
+                moved += 1;
+            } else {
+                assert_eq!(new, *old, "key {i} moved away from a backend that is still there");
+            }
+        }
+        // every backend has about a quarter of the keys
+        assert!(moved > 300 && moved < 700, "the removed backend had {moved} of 2000 keys");
+    }
+
+    #[test]
+    fn least_loaded_strategies_follow_the_weights_of_udp_backends() {
+        for strategy in [BalancingStrategy::LeastOutstanding, BalancingStrategy::PowerOfTwoChoices] {
+            let balancing = Balancing { strategy, ..Balancing::default() };
+            let pool = Pool::new("test",vec![
+                BackendServer::new(SocketAddr::from(([10,0,0,1],12201)),BackendProtocol::Udp,1),
+                BackendServer::new(SocketAddr::from(([10,0,0,2],12201)),BackendProtocol::Udp,3)
+            ],false,Duration::from_secs(10),balancing);
+            let message = GelfMessageWrapper::Simple(GelfPacket::new_simple(vec![],"192.168.1.10:5000".parse().unwrap()));
+            let light = (0..4000).filter(|_| pool.select(&message,None).unwrap().weight == 1).count();
+            assert_eq!(light, 1000, "{strategy:?}: the backend with 1/4 of the weight got {light} of 4000");
+        }
+    }
+
+    #[test]
+    fn duplicate_chunks_do_not_release_the_pin() {
+        let pool = pool(4,false);
+        let source = "192.168.1.10:5000";
+        let first = pool.select(&chunk(7,0,3,source),None).unwrap();
+        let pinned = || pool.pins.lock().unwrap().pinned.len();
+        assert_eq!(pinned(), 1);
+        assert_eq!(pool.select(&chunk(7,1,3,source),None).unwrap().addr, first.addr);
+        // a sender that retransmits (or a network that duplicates) must not use up the pin
+        assert_eq!(pool.select(&chunk(7,1,3,source),None).unwrap().addr, first.addr);
+        assert_eq!(pool.select(&chunk(7,0,3,source),None).unwrap().addr, first.addr);
+        assert_eq!(pinned(), 1);
+
+        // the last chunk still goes to the pinned backend when the backends change in the meantime
+        pool.set_backends(vec![BackendServer::new(SocketAddr::from(([10,0,0,9],12201)),BackendProtocol::Udp,1)]);
+        assert_eq!(pool.select(&chunk(7,2,3,source),None).unwrap().addr, first.addr);
+        assert_eq!(pinned(), 0);
+    }
+
+    #[test]
+    fn traffic_fails_over_to_the_next_priority_and_back() {
+        let circuit_breaker = CircuitBreaker { failure_threshold: 1, failure_window_seconds: 10, open_seconds: 30, half_open_successes: 1 };
+        let pool = Pool::new("test",vec![
+            BackendServer::new(SocketAddr::from(([10,0,0,1],12201)),BackendProtocol::Udp,1).with_circuit_breaker(Some(circuit_breaker)),
+            BackendServer::new(SocketAddr::from(([10,0,0,2],12201)),BackendProtocol::Udp,1),
+            BackendServer::new(SocketAddr::from(([10,0,0,3],12201)),BackendProtocol::Udp,1).with_priority(1)
+        ],false,Duration::from_secs(10),Balancing::default());
+        let [a, b, c] : [Arc<BackendServer>; 3] = pool.backends().try_into().unwrap();
+        let message = GelfMessageWrapper::Simple(GelfPacket::new_simple(vec![],"192.168.1.10:5000".parse().unwrap()));
+        let targets = || {
+            let mut targets : Vec<u8> = (0..10).map(|_| match pool.select(&message,None).unwrap().addr.ip() {
+                IpAddr::V4(x) => x.octets()[3],
+                IpAddr::V6(_) => unreachable!()
+            }).collect();
+            targets.sort_unstable();
+            targets.dedup();
+            targets
+        };
+        assert_eq!(targets(), vec![1,2]);
+
+        *a.healthy.write().unwrap() = false;
+        assert_eq!(targets(), vec![2]);
+        b.set_draining(true);
+        assert_eq!(targets(), vec![3]);
+
+        *a.healthy.write().unwrap() = true;
+        assert_eq!(targets(), vec![1]);
+        a.record_send(&Err(anyhow::anyhow!("icmp port unreachable")));
+        assert_eq!(a.circuit.lock().unwrap().state_name(), "open");
+        assert_eq!(targets(), vec![3]);
+
+        b.set_draining(false);
+        assert_eq!(targets(), vec![2]);
+        assert_eq!(*pool.active_priority.lock().unwrap(), Some(0));
+        assert!(c.allows_traffic(), "the standby backend is fine, it just is not needed");
+    }
+
+    #[test]
+    fn power_of_two_choices_always_compares_two_backends() {
+        let balancing = Balancing { strategy: BalancingStrategy::PowerOfTwoChoices, ..Balancing::default() };
+        let pool = Pool::new("test",vec![
+            BackendServer::new(SocketAddr::from(([10,0,0,1],12201)),BackendProtocol::Udp,1),
+            BackendServer::new(SocketAddr::from(([10,0,0,2],12201)),BackendProtocol::Udp,1)
+        ],true,Duration::from_secs(10),balancing);
+        // the first backend has a backlog, so whenever it is compared with the second one it loses
+        let busy = pool.backends()[0].clone();
+        let (sender, _receiver) = std::sync::mpsc::sync_channel(100);
+        busy.set_queue(sender);
+        for _ in 0..10 {
+            busy.enqueue(vec![]).unwrap();
+        }
+        let message = GelfMessageWrapper::Simple(GelfPacket::new_simple(vec![],"192.168.1.10:5000".parse().unwrap()));
+        for _ in 0..1000 {
+            assert_eq!(pool.select(&message,None).unwrap().addr, SocketAddr::from(([10,0,0,2],12201)));
+        }
+    }
+
+    #[test]
+    fn senders_with_the_same_ids_are_spread_with_chunk_affinity_by_source() {
+        let pool = pool(4,true);
+        let sources = ["192.168.1.10:5000","192.168.1.11:5000","192.168.1.12:5000","192.168.1.13:5000"];
+        let spread = (0..100).filter(|id| {
+            let backends : std::collections::HashSet<SocketAddr> = sources.iter().map(|x| pool.select(&chunk(*id,0,1,x),None).unwrap().addr).collect();
+            backends.len() > 1
+        }).count();
+        assert!(spread > 90, "only {spread} of 100 shared ids went to more than one backend");
+        assert_eq!(chunk_affinity_hash(42,None), chunk_affinity_hash(42,None));
+        assert_ne!(chunk_affinity_hash(42,Some("192.168.1.10".parse().unwrap())), chunk_affinity_hash(42,Some("192.168.1.11".parse().unwrap())));
+    }
+}
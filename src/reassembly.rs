@@ -0,0 +1,183 @@
+use std::{collections::{HashMap, VecDeque}, net::IpAddr, sync::{Mutex, RwLock}, time::{Duration, Instant}};
+
+use crate::{gelf::ChunkError, GelfChunkedMessage};
+
+type Key = (IpAddr,u64);
+
+// collects the chunks of messages until they are complete, and forgets messages that take too long
+#[derive(Debug)]
+pub struct Reassembly {
+    timeout: Duration,
+    pending: Mutex<Pending>,
+    stats: RwLock<ReassemblyStats>
+}
+
+// every message gets the same timeout, so the expiry queue is always in the order of the deadlines and a sweep only
+// has to look at the front of it. messages that complete stay in the queue until they would have expired, that is
+// cheaper than searching for them. the arrival time tells an expired message apart from a newer one with the same key.
+#[derive(Debug, Default)]
+struct Pending {
+    messages: HashMap<Key,GelfChunkedMessage>,
+    expiry: VecDeque<(Instant,Key)>
+}
+
+/// How many messages were put back together and how long they took, from the first chunk to the last.
+#[derive(Debug, Default, Clone)]
+pub struct ReassemblyStats {
+    pub completed: u64,
+    pub expired: u64,
+    pub total_time: Duration,
+    pub max_time: Duration
+}
+
+impl ReassemblyStats {
+    pub fn average_time(&self) -> Duration {
+        if self.completed == 0 {
+            return Duration::ZERO
+        }
+        self.total_time / self.completed.try_into().unwrap_or(u32::MAX)
+    }
+}
+
+impl Reassembly {
+
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout, pending: Mutex::new(Pending::default()), stats: RwLock::new(ReassemblyStats::default()) }
+    }
+
+    /// Adds the chunk of a message, returns the message once all of its chunks have arrived.
+    pub fn add(&self, mut chunked_pkg: GelfChunkedMessage) -> Result<Option<GelfChunkedMessage>,ChunkError> {
+        let key = chunked_pkg.reassembly_key();
+        let mut guard = self.pending.lock().unwrap();
+        match guard.messages.get_mut(&key) {
+            Some(old) => {
+                old.add_chunk(chunked_pkg.chunks.remove(0))?;
+                if !old.is_complete() {
+                    return Ok(None)
+                }
+            },
+            // messages of a single chunk are only seen when a sender chunks without a reason to, nothing to wait for
+            None if chunked_pkg.is_complete() => return Ok(Some(chunked_pkg)),
+            None => {
+                guard.expiry.push_back((chunked_pkg.arrival_time + self.timeout,key));
+                guard.messages.insert(key,chunked_pkg);
+                return Ok(None)
+            }
+        }
+        let completed = guard.messages.remove(&key).expect("a message that was just completed should still be pending");
+        drop(guard);
+        let elapsed = completed.arrival_time.elapsed();
+        let mut stats = self.stats.write().unwrap();
+        stats.completed += 1;
+        stats.total_time += elapsed;
+        stats.max_time = stats.max_time.max(elapsed);
+        Ok(Some(completed))
+    }
+
+    /// Forgets the messages that did not get all of their chunks in time, returns how many.
+    pub fn expire(&self) -> u64 {
+        let now = Instant::now();
+        let mut nr_expired = 0;
+        {
+            let mut guard = self.pending.lock().unwrap();
+            let Pending { messages, expiry } = &mut *guard;
+            while let Some(&(deadline,key)) = expiry.front() {
+                if deadline > now {
+                    break
+                }
+                expiry.pop_front();
+                if messages.get(&key).is_some_and(|x|x.arrival_time + self.timeout == deadline) {
+                    messages.remove(&key);
+                    nr_expired += 1;
+                }
+            }
+        }
+        if nr_expired > 0 {
+            self.stats.write().unwrap().expired += nr_expired;
+        }
+        nr_expired
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().messages.len()
+    }
+
+    pub fn stats(&self) -> ReassemblyStats {
+        self.stats.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GelfPacket;
+
+    fn chunk(id: u64, sequence_number: u8, total_chunks: u8, source: &str) -> GelfChunkedMessage {
+        let mut data = vec![0x1e,0x0f];
+        data.extend_from_slice(&id.to_be_bytes());
+        data.extend_from_slice(&[sequence_number,total_chunks,b'x']);
+        GelfChunkedMessage::new(GelfPacket::new_chunked(data,id,sequence_number,total_chunks,source.parse().unwrap()))
+    }
+
+    #[test]
+    fn messages_expire_when_their_time_is_up() {
+        let reassembly = Reassembly::new(Duration::from_millis(100));
+        assert!(reassembly.add(chunk(1,0,3,"10.0.0.1:5000")).unwrap().is_none());
+        assert!(reassembly.add(chunk(1,1,3,"10.0.0.1:5000")).unwrap().is_none());
+        assert_eq!(reassembly.expire(), 0);
+        assert_eq!(reassembly.pending(), 1);
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(reassembly.expire(), 1);
+        assert_eq!(reassembly.pending(), 0);
+        assert_eq!(reassembly.stats().expired, 1);
+
+        // the last chunk starts a new message, which has to wait for the others all over again
+        assert!(reassembly.add(chunk(1,2,3,"10.0.0.1:5000")).unwrap().is_none());
+        assert_eq!(reassembly.pending(), 1);
+    }
+
+    #[test]
+    fn completed_messages_do_not_expire() {
+        let reassembly = Reassembly::new(Duration::from_millis(100));
+        reassembly.add(chunk(1,1,2,"10.0.0.1:5000")).unwrap();
+        reassembly.add(chunk(2,0,2,"10.0.0.1:5000")).unwrap();
+        let completed = reassembly.add(chunk(1,0,2,"10.0.0.1:5000")).unwrap().expect("both chunks are there");
+        assert_eq!(completed.chunks.iter().map(|x|x.sequence_number).collect::<Vec<_>>(), vec![0,1]);
+
+        std::thread::sleep(Duration::from_millis(150));
+        // only the message that is still waiting counts, the completed one is just taken off the queue
+        assert_eq!(reassembly.expire(), 1);
+        let stats = reassembly.stats();
+        assert_eq!((stats.completed, stats.expired), (1, 1));
+        assert_eq!(reassembly.pending(), 0);
+    }
+
+    #[test]
+    fn a_newer_message_with_the_same_key_is_not_expired_with_the_old_one() {
+        let reassembly = Reassembly::new(Duration::from_millis(300));
+        reassembly.add(chunk(1,0,2,"10.0.0.1:5000")).unwrap();
+        reassembly.add(chunk(1,1,2,"10.0.0.1:5000")).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        reassembly.add(chunk(1,0,2,"10.0.0.1:5000")).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(reassembly.expire(), 0);
+        assert_eq!(reassembly.pending(), 1);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(reassembly.expire(), 1);
+    }
+
+    #[test]
+    fn duplicate_chunks_are_rejected() {
+        let reassembly = Reassembly::new(Duration::from_secs(10));
+        reassembly.add(chunk(1,0,3,"10.0.0.1:5000")).unwrap();
+        reassembly.add(chunk(1,2,3,"10.0.0.1:5000")).unwrap();
+        assert!(matches!(reassembly.add(chunk(1,2,3,"10.0.0.1:5000")), Err(ChunkError::Duplicate(2))));
+        assert!(matches!(reassembly.add(chunk(1,0,3,"10.0.0.1:5000")), Err(ChunkError::Duplicate(0))));
+        // the same id from another sender is another message
+        assert!(reassembly.add(chunk(1,2,3,"10.0.0.2:5000")).unwrap().is_none());
+        let completed = reassembly.add(chunk(1,1,3,"10.0.0.1:5000")).unwrap().expect("every chunk arrived once");
+        assert_eq!(completed.chunks.len(), 3);
+        assert_eq!(reassembly.pending(), 1);
+    }
+}
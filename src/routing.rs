@@ -0,0 +1,341 @@
+use std::{collections::HashMap, sync::{Arc, Mutex, RwLock}};
+
+use crate::{balancer::is_start_of_message, pool::{chunk_affinity_hash, hash_str, mix, Pool}, rules::Cidr, GelfMessage, GelfMessageWrapper};
+
+// where a route sends its messages: straight to a pool, or to one of the pools of a split
+#[derive(Debug)]
+enum Target {
+    Pool(Arc<Pool>),
+    Split(Arc<PoolSplit>)
+}
+
+impl Target {
+    fn name(&self) -> &str {
+        match self {
+            Target::Pool(x) => &x.name,
+            Target::Split(x) => &x.name
+        }
+    }
+}
+
+// the target of a configured network or route, in the same order as in the configuration
+#[derive(Debug)]
+struct RouteTarget {
+    target: Target,
+    hits: RwLock<u64>
+}
+
+// picks the pool for a message: the network of the sender, the first route that matches, or the default pool
+#[derive(Debug)]
+pub struct Router {
+    config: Arc<crate::Configuration>,
+    /// every source of every network with the index of its network, the most specific first
+    sources: Vec<(Cidr,usize)>,
+    networks: Vec<RouteTarget>,
+    routes: Vec<RouteTarget>,
+    default_target: Target,
+    default_hits: RwLock<u64>,
+    splits: Vec<Arc<PoolSplit>>
+}
+
+/// How often a network or route picked the pool for a message.
+pub struct RouteHits {
+    pub kind: RouteKind,
+    /// the name of the pool or split
+    pub pool: String,
+    pub hits: u64
+}
+
+/// What picked the pool, networks and routes are numbered in the order of the configuration.
+pub enum RouteKind {
+    Network(usize),
+    Route(usize),
+    Default
+}
+
+impl Router {
+
+    pub fn new(config: Arc<crate::Configuration>, pools: &[Arc<Pool>]) -> anyhow::Result<Self> {
+        let find_pool = |name: &str| pools.iter().find(|x|x.name == name).cloned()
+            .ok_or_else(||anyhow::anyhow!("there is no pool named {name:?}"));
+
+        let mut splits : Vec<Arc<PoolSplit>> = vec![];
+        for split in &config.splits {
+            if pools.iter().any(|x|x.name == split.name) || splits.iter().any(|x|x.name == split.name) {
+                anyhow::bail!("the name {:?} is used by more than one pool or split",split.name)
+            }
+            if split.pools.is_empty() {
+                anyhow::bail!("the split {:?} has no pools",split.name)
+            }
+            let shares = split.pools.iter().map(|x| {
+                if !x.percent.is_finite() || x.percent < 0.0 {
+                    anyhow::bail!("the split {:?} has an invalid percentage for pool {:?}",split.name,x.pool)
+                }
+                Ok(Share { pool: find_pool(&x.pool)?, percent: RwLock::new(x.percent), hits: RwLock::new(0) })
+            }).collect::<anyhow::Result<_>>()?;
+            splits.push(Arc::new(PoolSplit { name: split.name.clone(), hash_key: split.hash_key.clone(), shares, next: Mutex::new(0) }));
+        }
+
+        let find = |name: &str| match splits.iter().find(|x|x.name == name) {
+            Some(split) => Ok(Target::Split(split.clone())),
+            None => find_pool(name).map(Target::Pool)
+        };
+        let mut sources : Vec<(Cidr,usize)> = config.networks.iter().enumerate()
+            .flat_map(|(i,x)| x.sources.iter().map(move|cidr| (*cidr,i)))
+            .collect();
+        sources.sort_by_key(|(cidr,_)| std::cmp::Reverse(cidr.prefix_len()));
+        Ok(Self {
+            sources,
+            networks: config.networks.iter().map(|x| Ok(RouteTarget { target: find(&x.pool)?, hits: RwLock::new(0) }))
+                .collect::<anyhow::Result<_>>()?,
+            routes: config.routes.iter().map(|x| Ok(RouteTarget { target: find(&x.pool)?, hits: RwLock::new(0) }))
+                .collect::<anyhow::Result<_>>()?,
+            default_target: find(&config.default_pool)?,
+            default_hits: RwLock::new(0),
+            splits,
+            config
+        })
+    }
+
+    // networks only need the sender, so they also work when chunks are passed through one by one. all chunks of a message
+    // come from the same sender and go the same way. without a parsed message the routes are skipped.
+    pub fn route(&self, packet: &GelfMessageWrapper, message: Option<&GelfMessage>) -> &Arc<Pool> {
+        let source = packet.pkg_src().ip();
+        let route = self.sources.iter().find(|(cidr,_)|cidr.contains(source)).map(|(_,i)|&self.networks[*i])
+            .or_else(|| message.and_then(|msg| self.config.routes.iter().zip(&self.routes).find(|(x,_)|x.rule.matches(msg,source)).map(|(_,x)|x)));
+        let (hits, target) = match route {
+            Some(x) => (&x.hits, &x.target),
+            None => (&self.default_hits, &self.default_target)
+        };
+        let is_start = is_start_of_message(packet);
+        if is_start {
+            hits.write().map(|mut x| *x += 1).expect("should always be possible to increment route hit count");
+        }
+        match target {
+            Target::Pool(pool) => pool,
+            Target::Split(split) => split.pick(packet,message,is_start)
+        }
+    }
+
+    pub fn hits(&self) -> Vec<RouteHits> {
+        let hits = |kind: RouteKind, x: &RouteTarget| RouteHits { kind, pool: x.target.name().to_string(), hits: *x.hits.read().unwrap() };
+        let mut result : Vec<RouteHits> = self.networks.iter().enumerate().map(|(i,x)| hits(RouteKind::Network(i),x))
+            .chain(self.routes.iter().enumerate().map(|(i,x)| hits(RouteKind::Route(i),x)))
+            .collect();
+        result.push(RouteHits { kind: RouteKind::Default, pool: self.default_target.name().to_string(), hits: *self.default_hits.read().unwrap() });
+        result
+    }
+
+    pub fn splits(&self) -> &[Arc<PoolSplit>] {
+        &self.splits
+    }
+}
+
+// a pool of a split with its current share of the traffic
+#[derive(Debug)]
+struct Share {
+    pool: Arc<Pool>,
+    percent: RwLock<f64>,
+    hits: RwLock<u64>
+}
+
+/// How much of the traffic of a split a pool gets, and how many messages it got.
+pub struct ShareInfo {
+    pub pool: String,
+    pub percent: f64,
+    pub hits: u64
+}
+
+// spreads messages over pools by percentage. every message gets a position from 0 to 1, and the pools own consecutive
+// ranges of that in the configured order. the position of a key never changes, so when the share of the last pool grows
+// it only takes over keys from the pool before it, and keys that already went to it stay there.
+#[derive(Debug)]
+pub struct PoolSplit {
+    pub name: String,
+    hash_key: Option<String>,
+    shares: Vec<Share>,
+    /// messages without a key are spread by a counter
+    next: Mutex<u64>
+}
+
+impl PoolSplit {
+
+    fn pick(&self, packet: &GelfMessageWrapper, message: Option<&GelfMessage>, is_start: bool) -> &Arc<Pool> {
+        let position = self.position(packet,message);
+        let percents : Vec<f64> = self.shares.iter().map(|x|*x.percent.read().unwrap()).collect();
+        let total : f64 = percents.iter().sum();
+        // with all shares at 0 the first pool is the only sensible choice
+        let mut share = &self.shares[0];
+        let mut remaining = position * total;
+        for (candidate,percent) in self.shares.iter().zip(percents) {
+            if percent > 0.0 {
+                share = candidate;
+            }
+            if remaining < percent {
+                break
+            }
+            remaining -= percent;
+        }
+        if is_start {
+            share.hits.write().map(|mut x| *x += 1).expect("should always be possible to increment split hit count");
+        }
+        &share.pool
+    }
+
+    // 0.0 - 1.0. chunks that are passed through one by one have no message, they use their id so that they stay together.
+    fn position(&self, packet: &GelfMessageWrapper, message: Option<&GelfMessage>) -> f64 {
+        let key = match self.hash_key.as_deref() {
+            Some("source_ip") => Some(packet.pkg_src().ip().to_string()),
+            Some(field) => message.and_then(|x|x.field(field)),
+            None => None
+        };
+        let hash = match (key, packet.pkg_id()) {
+            (Some(key), _) => hash_str(&key),
+            (None, Some(id)) => chunk_affinity_hash(id, Some(packet.pkg_src().ip())),
+            (None, None) => {
+                let mut next = self.next.lock().unwrap();
+                *next = next.wrapping_add(1);
+                mix(*next)
+            }
+        };
+        hash as f64 / u64::MAX as f64
+    }
+
+    pub fn shares(&self) -> Vec<ShareInfo> {
+        self.shares.iter().map(|x| ShareInfo {
+            pool: x.pool.name.clone(),
+            percent: *x.percent.read().unwrap(),
+            hits: *x.hits.read().unwrap()
+        }).collect()
+    }
+
+    /// Changes the percentages of some or all pools of the split. nothing changes if one of them is invalid.
+    pub fn set_percents(&self, percents: &HashMap<String,f64>) -> anyhow::Result<()> {
+        for (pool,percent) in percents {
+            if !self.shares.iter().any(|x|&x.pool.name == pool) {
+                anyhow::bail!("the split {} has no pool named {pool:?}",self.name)
+            }
+            if !percent.is_finite() || *percent < 0.0 {
+                anyhow::bail!("{percent} is not a valid percentage")
+            }
+        }
+        for share in &self.shares {
+            if let Some(percent) = percents.get(&share.pool.name) {
+                *share.percent.write().unwrap() = *percent;
+                log::info!("split {} now sends {percent}% to pool {}",self.name,share.pool.name);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{configuration::Balancing, GelfPacket};
+
+    fn router(config: &str, names: &[&str]) -> Router {
+        let config : crate::Configuration = toml::from_str(&format!("listen_port = 0\n{config}")).unwrap();
+        let pools : Vec<Arc<Pool>> = names.iter().map(|x| Arc::new(Pool::new(x,vec![],false,Duration::from_secs(10),Balancing::default()))).collect();
+        Router::new(Arc::new(config),&pools).unwrap()
+    }
+
+    fn packet(source: &str) -> GelfMessageWrapper {
+        GelfMessageWrapper::Simple(GelfPacket::new_simple(vec![],source.parse().unwrap()))
+    }
+
+    fn message(host: &str, level: u8) -> GelfMessage {
+        serde_json::from_value(serde_json::json!({"version":"1.1","host":host,"short_message":"hello","level":level})).unwrap()
+    }
+
+    #[test]
+    fn the_first_matching_route_picks_the_pool() {
+        let router = router(r#"
+            [[routes]]
+            pool = "errors"
+            max_level = 3
+            [[routes]]
+            pool = "web"
+            host = "web-*"
+        "#,&["default","errors","web"]);
+        let route = |host: &str, level: u8| router.route(&packet("10.0.0.1:5000"),Some(&message(host,level))).name.clone();
+        assert_eq!(route("web-01",2), "errors");
+        assert_eq!(route("web-01",6), "web");
+        assert_eq!(route("db-01",6), "default");
+    }
+
+    #[test]
+    fn messages_that_match_no_route_go_to_the_default_pool() {
+        let router = router(r#"
+            default_pool = "fallback"
+            [[routes]]
+            pool = "web"
+            host = "web-*"
+        "#,&["default","fallback","web"]);
+        assert_eq!(router.route(&packet("10.0.0.1:5000"),Some(&message("db-01",6))).name, "fallback");
+        // packets that are passed through as they are have no message to match the routes with
+        assert_eq!(router.route(&packet("10.0.0.1:5000"),None).name, "fallback");
+        let hits = router.hits();
+        assert!(matches!(hits.last().unwrap().kind, RouteKind::Default));
+        assert_eq!(hits.last().unwrap().hits, 2);
+    }
+
+    #[test]
+    fn the_most_specific_network_wins_over_routes() {
+        let router = router(r#"
+            [[networks]]
+            pool = "office"
+            sources = ["10.0.0.0/8"]
+            [[networks]]
+            pool = "lab"
+            sources = ["10.1.0.0/16", "fd00::/8"]
+            [[routes]]
+            pool = "web"
+            host = "*"
+        "#,&["default","office","lab","web"]);
+        let route = |source: &str| router.route(&packet(source),None).name.clone();
+        assert_eq!(route("10.2.0.1:5000"), "office");
+        assert_eq!(route("10.1.0.1:5000"), "lab");
+        assert_eq!(route("[fd12::1]:5000"), "lab");
+        assert_eq!(route("192.168.0.1:5000"), "default");
+        assert_eq!(router.route(&packet("192.168.0.1:5000"),Some(&message("db-01",6))).name, "web");
+    }
+
+    #[test]
+    fn splits_follow_their_percentages_and_changes_to_them() {
+        let router = router(r#"
+            default_pool = "upgrade"
+            [[splits]]
+            name = "upgrade"
+            pools = [ { pool = "old", percent = 80 }, { pool = "new", percent = 20 } ]
+        "#,&["default","old","new"]);
+        let share_of_new = || (0..20_000).filter(|_| router.route(&packet("10.0.0.1:5000"),None).name == "new").count() as f64 / 20_000.0;
+        let share = share_of_new();
+        assert!((share - 0.2).abs() < 0.02, "the new pool got {share} instead of 0.2");
+
+        let split = &router.splits()[0];
+        split.set_percents(&HashMap::from([("old".to_string(),50.0),("new".to_string(),50.0)])).unwrap();
+        let share = share_of_new();
+        assert!((share - 0.5).abs() < 0.02, "the new pool got {share} instead of 0.5 after the change");
+
+        split.set_percents(&HashMap::from([("old".to_string(),0.0)])).unwrap();
+        assert_eq!(share_of_new(), 1.0);
+        let shares = split.shares();
+        assert_eq!((shares[0].percent, shares[1].percent), (0.0, 50.0));
+        assert!(shares[0].hits > 0 && shares[1].hits > 0);
+
+        // an invalid change leaves every share as it was
+        assert!(split.set_percents(&HashMap::from([("old".to_string(),10.0),("new".to_string(),-1.0)])).is_err());
+        assert!(split.set_percents(&HashMap::from([("missing".to_string(),10.0)])).is_err());
+        assert_eq!(split.shares()[0].percent, 0.0);
+    }
+
+    #[test]
+    fn routes_must_refer_to_existing_pools() {
+        let config : crate::Configuration = toml::from_str("listen_port = 0\ndefault_pool = \"missing\"").unwrap();
+        let pools = vec![Arc::new(Pool::new("default",vec![],false,Duration::from_secs(10),Balancing::default()))];
+        assert!(Router::new(Arc::new(config),&pools).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use std::net::IpAddr;
+
+use crate::{configuration::MatchRule, GelfMessage};
+
+impl MatchRule {
+    // all conditions that are set in a rule must hold for the rule to match
+    pub fn matches(&self, msg: &GelfMessage, source: IpAddr) -> bool {
+        if let Some(cidr) = &self.source {
+            if !cidr.contains(source) {
+                return false
+            }
+        }
+        if let Some(min_level) = self.min_level {
+            if msg.level.is_none_or(|x| x < min_level) {
+                return false
+            }
+        }
+        if let Some(max_level) = self.max_level {
+            if msg.level.is_none_or(|x| x > max_level) {
+                return false
+            }
+        }
+        if let Some(host) = &self.host {
+            if !glob_matches(host,&msg.host) {
+                return false
+            }
+        }
+        if let Some(facility) = &self.facility {
+            if msg.facility.as_ref() != Some(facility) {
+                return false
+            }
+        }
+        if !self.patterns.iter().all(|(name,pattern)| msg.field(name).is_some_and(|x|pattern.0.is_match(&x))) {
+            return false
+        }
+        self.fields.iter().all(|(name,expected)| match msg.field(name) {
+            Some(_) if expected == "*" => true,
+            Some(value) => &value == expected,
+            None => false
+        })
+    }
+}
+
+// * matches any number of characters, ? exactly one
+pub fn glob_matches(pattern: &str, value: &str) -> bool {
+    let pattern : Vec<char> = pattern.chars().collect();
+    let value : Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    // where to continue when what followed the last * turns out not to match
+    let mut backtrack : Option<(usize,usize)> = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p,v));
+                p += 1;
+            },
+            Some(c) if *c == '?' || *c == value[v] => {
+                p += 1;
+                v += 1;
+            },
+            _ => match backtrack {
+                Some((star,matched)) => {
+                    backtrack = Some((star,matched + 1));
+                    p = star + 1;
+                    v = matched + 1;
+                },
+                None => return false
+            }
+        }
+    }
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+/// A regular expression in the configuration, compiled when the configuration is loaded.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pattern(pub regex::Regex);
+
+impl TryFrom<String> for Pattern {
+    type Error = regex::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        regex::Regex::new(&value).map(Pattern)
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(value: Pattern) -> Self {
+        value.0.as_str().to_string()
+    }
+}
+
+/// A network like "10.0.0.0/8" or "fd00::/8". a plain address is a network of just that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8
+}
+
+impl Cidr {
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            // senders on ipv4 show up as mapped addresses when we listen on ipv6
+            (IpAddr::V4(_), IpAddr::V6(ip)) => ip.to_ipv4_mapped().is_some_and(|x|self.contains(IpAddr::V4(x))),
+            (IpAddr::V6(_), IpAddr::V4(_)) => false
+        }
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (network, prefix_len) = match value.split_once('/') {
+            Some((network,prefix_len)) => (network.parse::<IpAddr>()?, Some(prefix_len.parse::<u8>()?)),
+            None => (value.parse::<IpAddr>()?, None)
+        };
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max);
+        if prefix_len > max {
+            anyhow::bail!("invalid prefix length in {value}")
+        }
+        Ok(Cidr { network, prefix_len })
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(value: Cidr) -> Self {
+        format!("{}/{}",value.network,value.prefix_len)
+    }
+}
+
+// replaces {{field}} placeholders with the json escaped value of that field, so templates can be json documents.
+// {{source}} is the address that we received the message from.
+pub fn render_template(template: &str, msg: &GelfMessage, source: std::net::SocketAddr) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else { break };
+        result.push_str(&rest[..start]);
+        let name = rest[start+2..start+len].trim();
+        let value = if name == "source" { Some(source.to_string()) } else { msg.field(name) };
+        if let Some(value) = value {
+            let escaped = serde_json::to_string(&value).expect("should always be possible to serialize a string");
+            result.push_str(&escaped[1..escaped.len()-1]);
+        }
+        rest = &rest[start+len+2..];
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn cidr(value: &str) -> Cidr {
+        Cidr::try_from(value.to_string()).unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn message(host: &str) -> GelfMessage {
+        GelfMessage {
+            version: "1.1".into(), host: host.into(), short_message: "hello".into(), full_message: None,
+            timestamp: None, level: Some(3), facility: Some("auth".into()), file: None, line: None,
+            additional_fields: HashMap::from([("_app".to_string(),serde_json::Value::from("billing"))])
+        }
+    }
+
+    #[test]
+    fn cidr_edges() {
+        // /0 is everything of its family
+        assert!(cidr("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(cidr("0.0.0.0/0").contains(ip("0.0.0.0")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(ip("10.0.0.1")));
+
+        // /32 and a plain address are only that address
+        assert!(cidr("10.1.2.3/32").contains(ip("10.1.2.3")));
+        assert!(!cidr("10.1.2.3/32").contains(ip("10.1.2.4")));
+        assert_eq!(cidr("10.1.2.3"), cidr("10.1.2.3/32"));
+        assert_eq!(cidr("fd00::1"), cidr("fd00::1/128"));
+
+        // the host bits of the network do not matter
+        assert!(cidr("10.1.2.3/16").contains(ip("10.1.255.255")));
+        assert!(!cidr("10.1.2.3/16").contains(ip("10.2.0.0")));
+
+        assert!(cidr("fd00::/8").contains(ip("fdff:ffff::1")));
+        assert!(!cidr("fd00::/8").contains(ip("fe80::1")));
+        assert!(cidr("2001:db8::/127").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::/127").contains(ip("2001:db8::2")));
+
+        // ipv4 senders show up as mapped addresses when we listen on ipv6
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.9.8.7")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.0.0.1")));
+
+        assert!(Cidr::try_from("10.0.0.0/33".to_string()).is_err());
+        assert!(Cidr::try_from("fd00::/129".to_string()).is_err());
+        assert!(Cidr::try_from("10.0.0/8".to_string()).is_err());
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_matches("web-*","web-01"));
+        assert!(glob_matches("web-*","web-"));
+        assert!(!glob_matches("web-*","db-01"));
+        assert!(glob_matches("*.example.com","a.b.example.com"));
+        assert!(!glob_matches("*.example.com","example.com"));
+        assert!(glob_matches("web-??","web-01"));
+        assert!(!glob_matches("web-??","web-1"));
+        assert!(!glob_matches("web-??","web-001"));
+        assert!(glob_matches("*","anything"));
+        assert!(glob_matches("*",""));
+        assert!(glob_matches("a*b*c","axxbyyc"));
+        assert!(glob_matches("a*b*c","abcbc"));
+        assert!(!glob_matches("a*b*c","axxbyy"));
+        assert!(glob_matches("exact","exact"));
+        assert!(!glob_matches("exact","exactly"));
+        assert!(!glob_matches("","x"));
+    }
+
+    #[test]
+    fn rules_need_all_of_their_conditions() {
+        let rule = MatchRule {
+            host: Some("web-*".into()),
+            max_level: Some(3),
+            fields: HashMap::from([("_app".to_string(),"*".to_string())]),
+            source: Some(cidr("10.0.0.0/8")),
+            ..Default::default()
+        };
+        assert!(rule.matches(&message("web-01"),ip("10.1.1.1")));
+        assert!(!rule.matches(&message("db-01"),ip("10.1.1.1")));
+        assert!(!rule.matches(&message("web-01"),ip("192.168.1.1")));
+        let mut info = message("web-01");
+        info.level = Some(6);
+        assert!(!rule.matches(&info,ip("10.1.1.1")));
+        let mut without_app = message("web-01");
+        without_app.additional_fields.clear();
+        assert!(!rule.matches(&without_app,ip("10.1.1.1")));
+        assert!(MatchRule::default().matches(&message("anything"),ip("192.168.1.1")));
+    }
+}
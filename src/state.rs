@@ -1,10 +1,36 @@
-use std::collections::HashMap;
-use crate::GelfChunkedMessage;
+use std::{collections::HashMap, sync::Arc};
+use crate::{pool::Pool, reassembly::Reassembly, routing::Router};
 
 #[derive(Debug)]
 pub struct State {
-    pub chunked_messages : std::sync::Mutex<HashMap<u64,GelfChunkedMessage>>,
+    /// messages that we are still collecting chunks for, by sender and message id
+    pub chunked_messages : Reassembly,
     pub nr_of_forwarded_messages : std::sync::RwLock<u64>,
     pub nr_of_handled_udp_packets : std::sync::RwLock<u64>,
+    pub nr_of_dropped_messages : std::sync::RwLock<HashMap<String,u64>>,
+    /// chunks that we did not accept, the message they belong to might still make it
+    pub nr_of_dropped_chunks : std::sync::RwLock<HashMap<String,u64>>,
+    pub nr_of_mirrored_messages : std::sync::RwLock<u64>,
+    pub nr_of_mirror_failures : std::sync::RwLock<u64>,
+    pub nr_of_webhook_calls : std::sync::RwLock<u64>,
+    pub nr_of_failed_webhook_calls : std::sync::RwLock<u64>,
+    pub nr_of_rate_limited_webhook_calls : std::sync::RwLock<u64>,
+    pub nr_of_archived_messages : std::sync::RwLock<u64>,
+    /// messages that did not fit in the archive queue or were part of an upload that failed
+    pub nr_of_archive_failures : std::sync::RwLock<u64>,
+    pub pools : Vec<Arc<Pool>>,
+    pub router : Router,
     pub otf_massage_required: bool
-}
\ No newline at end of file
+}
+
+impl State {
+    pub fn count_dropped(&self, reason: &str) {
+        let mut guard = self.nr_of_dropped_messages.write().expect("should always be possible to increment drop count");
+        *guard.entry(reason.to_string()).or_default() += 1;
+    }
+
+    pub fn count_dropped_chunk(&self, reason: &str) {
+        let mut guard = self.nr_of_dropped_chunks.write().expect("should always be possible to increment chunk drop count");
+        *guard.entry(reason.to_string()).or_default() += 1;
+    }
+}
@@ -0,0 +1,88 @@
+use std::{collections::HashMap, io::Write, net::{SocketAddr, TcpStream}, sync::{mpsc::Receiver, Arc, Weak}, time::{Duration, Instant}};
+use anyhow::Context;
+
+use crate::{configuration::BackendProtocol, pool::BackendServer};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// messages that can wait for a tcp or http backend, more than this are not accepted for it
+pub const QUEUE_SIZE: usize = 1000;
+const HTTP_PATH: &str = "/gelf";
+
+// every tcp and http backend gets its own sending thread and queue, so that a slow backend only holds up its own messages
+// and the balancer can see how far behind each backend is. the thread stops once the backend has been removed from its pool
+// and everything that was queued for it has been sent.
+pub fn spawn(backend: &Arc<BackendServer>) {
+    if !backend.protocol.is_stream() {
+        return
+    }
+    let (sender, receiver) = std::sync::mpsc::sync_channel::<Vec<u8>>(QUEUE_SIZE);
+    if backend.set_queue(sender) {
+        let (addr, protocol, backend) = (backend.addr, backend.protocol, Arc::downgrade(backend));
+        std::thread::spawn(move||stream_sender(backend,addr,protocol,receiver));
+    }
+}
+
+fn stream_sender(backend: Weak<BackendServer>, addr: SocketAddr, protocol: BackendProtocol, receiver: Receiver<Vec<u8>>) {
+    let mut connections = StreamConnections::default();
+    let agent = ureq::AgentBuilder::new().timeout_connect(CONNECT_TIMEOUT).timeout(WRITE_TIMEOUT).build();
+    while let Ok(frame) = receiver.recv() {
+        let started = Instant::now();
+        let result = match protocol {
+            BackendProtocol::Http => post(&agent,addr,&frame),
+            _ => connections.send(addr,&frame)
+        };
+        if let Err(e) = &result {
+            log::error!("failed to forward a message! {e:#}.");
+        }
+        if let Some(backend) = backend.upgrade() {
+            backend.record_sent(started.elapsed(),&result);
+        }
+    }
+}
+
+// the http input wants the json without the terminating null byte of the tcp frame
+pub fn post(agent: &ureq::Agent, backend: SocketAddr, frame: &[u8]) -> anyhow::Result<()> {
+    let json = frame.strip_suffix(&[0]).unwrap_or(frame);
+    agent.post(&format!("http://{backend}{HTTP_PATH}"))
+        .set("Content-Type","application/json")
+        .send_bytes(json)
+        .context(format!("failed to post to http backend {backend}"))?;
+    Ok(())
+}
+
+// keeps one open tcp connection per stream backend so that we do not have to reconnect for every message
+#[derive(Default)]
+pub struct StreamConnections {
+    connections: HashMap<SocketAddr, TcpStream>
+}
+
+impl StreamConnections {
+
+    pub fn send(&mut self, backend: SocketAddr, frame: &[u8]) -> anyhow::Result<()> {
+        // a connection that was closed by the other side is only noticed when we write to it,
+        // so we give it one more try on a fresh connection before giving up on the frame.
+        for attempt in 0..2 {
+            let stream = match self.connections.entry(backend) {
+                std::collections::hash_map::Entry::Occupied(x) => x.into_mut(),
+                std::collections::hash_map::Entry::Vacant(x) => {
+                    let stream = TcpStream::connect_timeout(&backend, CONNECT_TIMEOUT).context(format!("failed to connect to stream backend {backend}"))?;
+                    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                    stream.set_nodelay(true)?;
+                    x.insert(stream)
+                }
+            };
+            match stream.write_all(frame) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    self.connections.remove(&backend);
+                    if attempt > 0 {
+                        return Err(e).context(format!("failed to write to stream backend {backend}"))
+                    }
+                    log::debug!("stream connection to {backend} failed ({e}), reconnecting");
+                }
+            }
+        }
+        unreachable!()
+    }
+}
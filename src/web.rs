@@ -1,5 +1,9 @@
 use axum::{
-    routing::get,
+    extract::Request,
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{get, put},
     Router
 };
 use serde::Serialize;
@@ -10,14 +14,19 @@ use utoipa::{OpenApi, ToSchema};
 
 use crate::web::json::*;
 use crate::web::html::*;
+use crate::web::drain::*;
+use crate::web::split::*;
 
 #[derive(OpenApi)]
 #[openapi(
     paths(
         json_handler,
-        html_handler
+        html_handler,
+        drain_handler,
+        undrain_handler,
+        split_handler
     ),
-    components(schemas(Info))
+    components(schemas(Info,BackendInfo,RouteInfo,SplitInfo,ReassemblyInfo))
 )]
 struct ApiDoc;
 
@@ -31,28 +40,60 @@ pub struct AppState {
 pub async fn run(state:std::sync::Arc<crate::State>,config:std::sync::Arc<crate::Configuration>) {
 
     let port = config.web_ui_port.unwrap_or(8080);
+    let ip = match &config.web_ui_ip {
+        Some(x) => x.parse().expect("Invalid web_ui_ip"),
+        None => std::net::IpAddr::from(std::net::Ipv4Addr::UNSPECIFIED)
+    };
+
+    let address = SocketAddr::from((ip, port));
+    let listener = TcpListener::bind(&address).await.unwrap();
+    log::info!("web-ui started at {address:?}");
+    axum::serve(listener, app(state,config).into_make_service()).await.unwrap();
+}
+
+fn app(state:std::sync::Arc<crate::State>,config:std::sync::Arc<crate::Configuration>) -> Router {
 
     let mut oa = ApiDoc::openapi();
     oa.info.title = "GELF-LB".into();
     oa.info.version = "0.0.1".into();
 
-    let app = Router::new()
+    let app_state = AppState {
+        config: config.clone(),
+        state: state
+    };
+
+    let mut app = Router::new()
         .merge(
             utoipa_swagger_ui::SwaggerUi::new("/api-docs").url("/api-docs/openapi.json", oa)
         )
         .route("/json", get(json::json_handler))
         .route("/html", get(html::html_handler))
-        .route("/", get(html::html_handler))
-        .with_state(AppState {
-            config: config,
-            state: state
-        })  
-        ;
-       
-    let address = SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, port));
-    let listener = TcpListener::bind(&address).await.unwrap();
-    log::info!("web-ui started at {address:?}");
-    axum::serve(listener, app.into_make_service()).await.unwrap();
+        .route("/", get(html::html_handler));
+
+    // anyone who can reach the web ui could take backends out of service or move traffic to other pools, so this needs
+    // a token or a web ui that is only reachable from where it was meant to be
+    if config.web_api_token.is_some() || config.web_ui_ip.is_some() {
+        app = app.merge(Router::new()
+            .route("/backends/:address/drain", put(drain::drain_handler).delete(drain::undrain_handler))
+            .route("/splits/:name", put(split::split_handler))
+            .route_layer(axum::middleware::from_fn_with_state(app_state.clone(), authorize)));
+    } else {
+        log::info!("draining backends and changing splits through the web api is disabled, set web_api_token or web_ui_ip to enable it");
+    }
+
+    app.with_state(app_state)
+}
+
+// without a token every request that reaches the endpoint is allowed, the bind address is what protects it
+async fn authorize(state: axum::extract::State<AppState>, request: Request, next: Next) -> Response {
+    if let Some(token) = &state.config.web_api_token {
+        let expected = format!("Bearer {token}");
+        let given = request.headers().get(axum::http::header::AUTHORIZATION).map(|x|x.as_bytes());
+        if given != Some(expected.as_bytes()) {
+            return (StatusCode::UNAUTHORIZED, "a valid bearer token is required").into_response()
+        }
+    }
+    next.run(request).await
 }
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -75,10 +116,58 @@ pub mod html {
         let notes = "<br/><p class='faded'> * total forwarded messages will typically be lower than the udp count due to invalid incoming data and the fact that a single message can consist of multiple udp packets (chunked mode)</p>";
 
         let make_row = |k:&str,v:&str| format!("<tr><td>{k}</td><td>{v}</td></tr>");
-        let rows = [
+        let mut rows = vec![
             make_row("total seen incoming udp packets",&state.state.nr_of_handled_udp_packets.read().unwrap().to_string()),
             make_row("* total forwarded messages",&state.state.nr_of_forwarded_messages.read().unwrap().to_string())
-        ].join("\n");
+        ];
+        if cfg.mirror.is_some() {
+            rows.push(make_row("mirrored messages",&state.state.nr_of_mirrored_messages.read().unwrap().to_string()));
+            rows.push(make_row("failed or skipped mirror messages",&state.state.nr_of_mirror_failures.read().unwrap().to_string()));
+        }
+        if !cfg.webhooks.is_empty() {
+            rows.push(make_row("webhook calls",&state.state.nr_of_webhook_calls.read().unwrap().to_string()));
+            rows.push(make_row("failed webhook calls",&state.state.nr_of_failed_webhook_calls.read().unwrap().to_string()));
+            rows.push(make_row("rate limited webhook calls",&state.state.nr_of_rate_limited_webhook_calls.read().unwrap().to_string()));
+        }
+        if cfg.archive.is_some() {
+            rows.push(make_row("archived messages",&state.state.nr_of_archived_messages.read().unwrap().to_string()));
+            rows.push(make_row("failed or skipped archive messages",&state.state.nr_of_archive_failures.read().unwrap().to_string()));
+        }
+        if state.state.otf_massage_required {
+            let reassembly = super::reassembly_info(&state.state);
+            rows.push(make_row("messages waiting for chunks",&reassembly.pending.to_string()));
+            rows.push(make_row("messages put together from chunks",&format!("{} (average {:.1} ms, slowest {:.1} ms)",reassembly.completed,reassembly.average_ms,reassembly.max_ms)));
+            rows.push(make_row("messages that did not get all chunks in time",&reassembly.expired.to_string()));
+        }
+        for (reason,count) in state.state.nr_of_dropped_messages.read().unwrap().iter() {
+            rows.push(make_row(&format!("dropped messages ({reason})"),&count.to_string()));
+        }
+        for (reason,count) in state.state.nr_of_dropped_chunks.read().unwrap().iter() {
+            rows.push(make_row(&format!("dropped chunks ({reason})"),&count.to_string()));
+        }
+        if !cfg.routes.is_empty() || !cfg.networks.is_empty() {
+            for route in super::route_infos(&state.state) {
+                rows.push(make_row(&format!("messages routed to pool {} by {}",route.pool,route.route),&route.hits.to_string()));
+            }
+        }
+        for split in super::split_infos(&state.state) {
+            rows.push(make_row(&format!("messages split to pool {} by {} ({}%)",split.pool,split.split,split.percent),&split.hits.to_string()));
+        }
+        for backend in super::backend_infos(&state.state) {
+            rows.push(make_row(
+                &format!("{} backend {} (priority {}, weight {}, {:.1}% configured{}{}{}{})",backend.pool,backend.address,backend.priority,backend.weight,backend.configured_share * 100.0,
+                    if backend.healthy { "" } else { ", unhealthy" },
+                    if backend.draining { ", draining" } else { "" },
+                    if backend.slow_start_progress < 1.0 { format!(", slow start {:.0}%",backend.slow_start_progress * 100.0) } else { String::new() },
+                    if backend.circuit == "closed" { String::new() } else { format!(", circuit {}",backend.circuit) }),
+                &format!("{} messages ({:.1}%), {} send failures{}",backend.nr_of_forwarded_messages,backend.effective_share * 100.0,backend.nr_of_send_failures,
+                    match backend.latency_ms {
+                        Some(latency) => format!(", {} pending, {latency:.1} ms per message",backend.pending),
+                        None => String::new()
+                    })
+            ));
+        }
+        let rows = rows.join("\n");
         let html = include_str!("../ui.html")
             .replace("$title","GELF-LB UI")
             .replace("$header","GELF-LB")
@@ -94,7 +183,134 @@ pub mod html {
 #[derive(Serialize, ToSchema)]
 pub struct Info {
     nr_of_forwarded_messages : u64,
-    nr_of_handled_udp_packets : u64
+    nr_of_handled_udp_packets : u64,
+    nr_of_dropped_messages : std::collections::HashMap<String,u64>,
+    nr_of_dropped_chunks : std::collections::HashMap<String,u64>,
+    nr_of_mirrored_messages : u64,
+    nr_of_mirror_failures : u64,
+    nr_of_webhook_calls : u64,
+    nr_of_failed_webhook_calls : u64,
+    nr_of_rate_limited_webhook_calls : u64,
+    nr_of_archived_messages : u64,
+    nr_of_archive_failures : u64,
+    backends : Vec<BackendInfo>,
+    routes : Vec<RouteInfo>,
+    splits : Vec<SplitInfo>,
+    reassembly : ReassemblyInfo
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RouteInfo {
+    /// "network 1" or "route 1" for the first configured network or route etc, "default route" for messages that matched none
+    route : String,
+    pool : String,
+    hits : u64
+}
+
+fn route_infos(state: &crate::State) -> Vec<RouteInfo> {
+    state.router.hits().into_iter().map(|x| RouteInfo {
+        route: match x.kind {
+            crate::routing::RouteKind::Network(i) => format!("network {}",i + 1),
+            crate::routing::RouteKind::Route(i) => format!("route {}",i + 1),
+            crate::routing::RouteKind::Default => "default route".into()
+        },
+        pool: x.pool,
+        hits: x.hits
+    }).collect()
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SplitInfo {
+    split : String,
+    pool : String,
+    /// the current share of the pool, relative to the other pools of the split
+    percent : f64,
+    hits : u64
+}
+
+fn split_infos(state: &crate::State) -> Vec<SplitInfo> {
+    state.router.splits().iter().flat_map(|split| split.shares().into_iter().map(|x| SplitInfo {
+        split: split.name.clone(),
+        pool: x.pool,
+        percent: x.percent,
+        hits: x.hits
+    })).collect()
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ReassemblyInfo {
+    /// messages that are still waiting for chunks
+    pending : usize,
+    completed : u64,
+    /// messages that did not get all of their chunks in time
+    expired : u64,
+    /// from the first chunk of a message to the last
+    average_ms : f64,
+    max_ms : f64
+}
+
+fn reassembly_info(state: &crate::State) -> ReassemblyInfo {
+    let stats = state.chunked_messages.stats();
+    ReassemblyInfo {
+        pending: state.chunked_messages.pending(),
+        completed: stats.completed,
+        expired: stats.expired,
+        average_ms: stats.average_time().as_secs_f64() * 1000.0,
+        max_ms: stats.max_time.as_secs_f64() * 1000.0
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BackendInfo {
+    pool : String,
+    address : String,
+    weight : u32,
+    priority : u32,
+    /// share of the traffic of the backend's priority group according to the weights, 0.0 - 1.0
+    configured_share : f64,
+    /// share of the pool's forwarded messages that actually went to this backend, 0.0 - 1.0
+    effective_share : f64,
+    /// false while the health check says the backend is down, it gets no traffic then
+    healthy : bool,
+    /// closed, open or half-open
+    circuit : String,
+    /// a draining backend gets no new messages
+    draining : bool,
+    /// 0.0 - 1.0, how much of its weight a backend gets while it ramps up after coming back
+    slow_start_progress : f64,
+    nr_of_forwarded_messages : u64,
+    nr_of_send_failures : u64,
+    /// messages queued for a tcp or http backend that it did not get yet
+    pending : u64,
+    /// moving average of how long sending a message to a tcp or http backend takes
+    latency_ms : Option<f64>
+}
+
+fn backend_infos(state: &crate::State) -> Vec<BackendInfo> {
+    let mut result = vec![];
+    for pool in &state.pools {
+        let counts : Vec<u64> = pool.backends().iter().map(|x|*x.nr_of_forwarded_messages.read().unwrap()).collect();
+        let total : u64 = counts.iter().sum();
+        for (backend,count) in pool.backends().iter().zip(counts) {
+            result.push(BackendInfo {
+                pool: pool.name.clone(),
+                address: backend.addr.to_string(),
+                weight: backend.weight,
+                priority: backend.priority,
+                configured_share: pool.configured_share(backend),
+                effective_share: if total == 0 { 0.0 } else { count as f64 / total as f64 },
+                healthy: backend.is_healthy(),
+                circuit: backend.circuit.lock().unwrap().state_name().to_string(),
+                draining: backend.is_draining(),
+                slow_start_progress: pool.slow_start_progress(backend),
+                nr_of_forwarded_messages: count,
+                nr_of_send_failures: *backend.nr_of_send_failures.read().unwrap(),
+                pending: backend.pending(),
+                latency_ms: backend.latency().map(|x|x.as_secs_f64() * 1000.0)
+            })
+        }
+    }
+    result
 }
 
 pub mod json {
@@ -111,7 +327,222 @@ pub mod json {
     pub async fn json_handler(state: axum::extract::State<super::AppState>,) -> axum::Json<Info> {
        axum::Json(Info {
             nr_of_forwarded_messages : *state.state.nr_of_forwarded_messages.read().unwrap(),
-            nr_of_handled_udp_packets : *state.state.nr_of_handled_udp_packets.read().unwrap()
+            nr_of_handled_udp_packets : *state.state.nr_of_handled_udp_packets.read().unwrap(),
+            nr_of_dropped_messages : state.state.nr_of_dropped_messages.read().unwrap().clone(),
+            nr_of_dropped_chunks : state.state.nr_of_dropped_chunks.read().unwrap().clone(),
+            nr_of_mirrored_messages : *state.state.nr_of_mirrored_messages.read().unwrap(),
+            nr_of_mirror_failures : *state.state.nr_of_mirror_failures.read().unwrap(),
+            nr_of_webhook_calls : *state.state.nr_of_webhook_calls.read().unwrap(),
+            nr_of_failed_webhook_calls : *state.state.nr_of_failed_webhook_calls.read().unwrap(),
+            nr_of_rate_limited_webhook_calls : *state.state.nr_of_rate_limited_webhook_calls.read().unwrap(),
+            nr_of_archived_messages : *state.state.nr_of_archived_messages.read().unwrap(),
+            nr_of_archive_failures : *state.state.nr_of_archive_failures.read().unwrap(),
+            backends : super::backend_infos(&state.state),
+            routes : super::route_infos(&state.state),
+            splits : super::split_infos(&state.state),
+            reassembly : super::reassembly_info(&state.state)
+        })
+    }
+}
+pub mod drain {
+    use axum::http::StatusCode;
+
+    #[derive(serde::Deserialize, utoipa::IntoParams)]
+    pub struct DrainParams {
+        /// only the backend in this pool, by default the backend is drained in every pool it is in
+        pool: Option<String>
+    }
+
+    #[utoipa::path(
+        put,
+        tag = "BACKENDS",
+        path = "/backends/{address}/drain",
+        params(("address" = String, Path, description = "ip:port of the backend"), DrainParams),
+        responses(
+            (status = 204, description = "the backend gets no new messages, chunks of messages it already got still go to it"),
+            (status = 404, description = "there is no such backend")
+        )
+    )]
+    pub async fn drain_handler(state: axum::extract::State<super::AppState>, address: axum::extract::Path<String>, params: axum::extract::Query<DrainParams>) -> StatusCode {
+        set_draining(&state.state,&address,params.pool.as_deref(),true)
+    }
+
+    #[utoipa::path(
+        delete,
+        tag = "BACKENDS",
+        path = "/backends/{address}/drain",
+        params(("address" = String, Path, description = "ip:port of the backend"), DrainParams),
+        responses(
+            (status = 204, description = "the backend gets traffic again, ramping up if the pool has a slow start"),
+            (status = 404, description = "there is no such backend")
+        )
+    )]
+    pub async fn undrain_handler(state: axum::extract::State<super::AppState>, address: axum::extract::Path<String>, params: axum::extract::Query<DrainParams>) -> StatusCode {
+        set_draining(&state.state,&address,params.pool.as_deref(),false)
+    }
+
+    fn set_draining(state: &crate::State, address: &str, pool: Option<&str>, draining: bool) -> StatusCode {
+        let Ok(address) = address.parse::<std::net::SocketAddr>() else {
+            return StatusCode::NOT_FOUND
+        };
+        let backends : Vec<_> = state.pools.iter()
+            .filter(|x| pool.is_none_or(|name| x.name == name))
+            .flat_map(|x| x.backends())
+            .filter(|x| x.addr == address)
+            .collect();
+        if backends.is_empty() {
+            return StatusCode::NOT_FOUND
+        }
+        for backend in backends {
+            backend.set_draining(draining);
+        }
+        StatusCode::NO_CONTENT
+    }
+}
+
+pub mod split {
+    use axum::http::StatusCode;
+
+    #[utoipa::path(
+        put,
+        tag = "SPLITS",
+        path = "/splits/{name}",
+        params(("name" = String, Path, description = "name of the split")),
+        request_body(content = std::collections::HashMap<String, f64>, description = "the new percentage per pool, pools that are left out keep theirs", example = json!({"graylog6": 10.0, "default": 90.0})),
+        responses(
+            (status = 204, description = "the new percentages are used from now on"),
+            (status = 400, description = "a pool is not part of the split or a percentage is invalid"),
+            (status = 404, description = "there is no such split")
+        )
+    )]
+    pub async fn split_handler(state: axum::extract::State<super::AppState>, name: axum::extract::Path<String>, percents: axum::Json<std::collections::HashMap<String,f64>>) -> (StatusCode, String) {
+        let Some(split) = state.state.router.splits().iter().find(|x|x.name == *name) else {
+            return (StatusCode::NOT_FOUND, format!("there is no split named {:?}",*name))
+        };
+        match split.set_percents(&percents) {
+            Ok(()) => (StatusCode::NO_CONTENT, String::new()),
+            Err(e) => (StatusCode::BAD_REQUEST, e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, future::IntoFuture, sync::{Arc, RwLock}, time::Duration};
+
+    use crate::{configuration::{Balancing, BackendProtocol}, pool::{BackendServer, Pool}, reassembly::Reassembly, routing::Router};
+
+    const BACKEND: &str = "10.0.0.1:12201";
+
+    fn state(config: &Arc<crate::Configuration>) -> Arc<crate::State> {
+        let pools = vec![Arc::new(Pool::new("default",vec![BackendServer::new(BACKEND.parse().unwrap(),BackendProtocol::Udp,1)],false,Duration::from_secs(10),Balancing::default()))];
+        Arc::new(crate::State {
+            chunked_messages: Reassembly::new(Duration::from_secs(10)),
+            nr_of_forwarded_messages: RwLock::new(0),
+            nr_of_handled_udp_packets: RwLock::new(0),
+            nr_of_dropped_messages: RwLock::new(HashMap::new()),
+            nr_of_dropped_chunks: RwLock::new(HashMap::new()),
+            nr_of_mirrored_messages: RwLock::new(0),
+            nr_of_mirror_failures: RwLock::new(0),
+            nr_of_webhook_calls: RwLock::new(0),
+            nr_of_failed_webhook_calls: RwLock::new(0),
+            nr_of_rate_limited_webhook_calls: RwLock::new(0),
+            nr_of_archived_messages: RwLock::new(0),
+            nr_of_archive_failures: RwLock::new(0),
+            router: Router::new(config.clone(),&pools).unwrap(),
+            pools,
+            otf_massage_required: false
         })
     }
-}
\ No newline at end of file
+
+    // serves the web api on a free port and sends it requests, returns the status codes
+    async fn send(config: &str, requests: Vec<(&'static str, &'static str, Option<&'static str>, &'static str)>) -> (Vec<u16>, Arc<crate::State>) {
+        let config : Arc<crate::Configuration> = Arc::new(toml::from_str(&format!("listen_port = 0\n{config}")).unwrap());
+        let state = state(&config);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(axum::serve(listener, super::app(state.clone(),config).into_make_service()).into_future());
+        let statuses = tokio::task::spawn_blocking(move|| requests.into_iter().map(|(method,path,token,body)| {
+            let mut request = ureq::request(method,&format!("http://{address}{path}"));
+            if let Some(token) = token {
+                request = request.set("Authorization",&format!("Bearer {token}"));
+            }
+            match request.set("Content-Type","application/json").send_string(body) {
+                Ok(x) => x.status(),
+                Err(ureq::Error::Status(status,_)) => status,
+                Err(e) => panic!("request failed: {e}")
+            }
+        }).collect()).await.unwrap();
+        (statuses, state)
+    }
+
+    fn is_draining(state: &crate::State) -> bool {
+        state.pools[0].backends()[0].is_draining()
+    }
+
+    #[tokio::test]
+    async fn draining_is_disabled_without_a_token_or_bind_address() {
+        let (statuses, state) = send("",vec![("PUT","/backends/10.0.0.1:12201/drain",None,"")]).await;
+        assert_eq!(statuses, vec![404]);
+        assert!(!is_draining(&state));
+    }
+
+    #[tokio::test]
+    async fn draining_needs_the_token() {
+        let (statuses, state) = send("web_api_token = \"secret\"",vec![
+            ("PUT","/backends/10.0.0.1:12201/drain",None,""),
+            ("PUT","/backends/10.0.0.1:12201/drain",Some("wrong"),"")
+        ]).await;
+        assert_eq!(statuses, vec![401,401]);
+        assert!(!is_draining(&state));
+
+        let (statuses, state) = send("web_api_token = \"secret\"",vec![("PUT","/backends/10.0.0.1:12201/drain",Some("secret"),"")]).await;
+        assert_eq!(statuses, vec![204]);
+        assert!(is_draining(&state));
+
+        let (statuses, state) = send("web_api_token = \"secret\"",vec![
+            ("PUT","/backends/10.0.0.1:12201/drain",Some("secret"),""),
+            ("DELETE","/backends/10.0.0.1:12201/drain",Some("secret"),""),
+            ("PUT","/backends/10.9.9.9:12201/drain",Some("secret"),"")
+        ]).await;
+        assert_eq!(statuses, vec![204,204,404]);
+        assert!(!is_draining(&state));
+    }
+
+    #[tokio::test]
+    async fn draining_is_allowed_on_a_configured_bind_address() {
+        let (statuses, state) = send("web_ui_ip = \"127.0.0.1\"",vec![("PUT","/backends/10.0.0.1:12201/drain",None,"")]).await;
+        assert_eq!(statuses, vec![204]);
+        assert!(is_draining(&state));
+    }
+
+    #[tokio::test]
+    async fn changing_splits_needs_the_token() {
+        let config = r#"
+            web_api_token = "secret"
+            [[splits]]
+            name = "upgrade"
+            pools = [ { pool = "default", percent = 100 } ]
+        "#;
+        let (statuses, state) = send(config,vec![("PUT","/splits/upgrade",None,"")]).await;
+        assert_eq!(statuses, vec![401]);
+        assert_eq!(state.router.splits()[0].shares()[0].percent, 100.0);
+
+        let (statuses, state) = send(config,vec![
+            ("PUT","/splits/upgrade",Some("secret"),r#"{"default": 50}"#),
+            ("PUT","/splits/missing",Some("secret"),r#"{"default": 50}"#),
+            ("PUT","/splits/upgrade",Some("secret"),r#"{"missing": 50}"#)
+        ]).await;
+        assert_eq!(statuses, vec![204,404,400]);
+        assert_eq!(state.router.splits()[0].shares()[0].percent, 50.0);
+
+        let (statuses, _) = send(&config.replace("web_api_token = \"secret\"",""),vec![("PUT","/splits/upgrade",None,r#"{"default": 50}"#)]).await;
+        assert_eq!(statuses, vec![404]);
+    }
+
+    #[tokio::test]
+    async fn reading_needs_no_token() {
+        let (statuses, _) = send("web_api_token = \"secret\"",vec![("GET","/json",None,"")]).await;
+        assert_eq!(statuses, vec![200]);
+    }
+}
@@ -0,0 +1,101 @@
+use std::{net::SocketAddr, sync::{mpsc::{SyncSender, TrySendError}, Arc}, time::{Duration, Instant}};
+
+use serde_json::json;
+
+use crate::{rules::render_template, GelfMessage};
+
+const WEBHOOK_QUEUE_SIZE: usize = 100;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct WebhookCall {
+    url: String,
+    body: String
+}
+
+// counts calls per webhook in one minute windows
+struct RateLimit {
+    window_start: Instant,
+    calls_in_window: u32
+}
+
+// the balancer side of the webhooks: matches messages and renders the bodies, the actual requests are made on another thread
+pub struct WebhookSender {
+    state: Arc<crate::State>,
+    config: Arc<crate::Configuration>,
+    rate_limits: Vec<RateLimit>,
+    sender: SyncSender<WebhookCall>
+}
+
+impl WebhookSender {
+
+    pub fn inspect(&mut self, msg: &GelfMessage, source: SocketAddr) {
+        for (webhook,rate_limit) in self.config.webhooks.iter().zip(self.rate_limits.iter_mut()) {
+
+            if !webhook.rules.is_empty() && !webhook.rules.iter().any(|x|x.matches(msg,source.ip())) {
+                continue
+            }
+
+            if rate_limit.window_start.elapsed() >= Duration::from_secs(60) {
+                rate_limit.window_start = Instant::now();
+                rate_limit.calls_in_window = 0;
+            }
+            if rate_limit.calls_in_window >= webhook.max_per_minute {
+                log::trace!("not calling {} as it has reached its rate limit",webhook.url);
+                self.state.nr_of_rate_limited_webhook_calls.write().map(|mut x| *x += 1)
+                    .expect("should always be possible to increment rate limited webhook count");
+                continue
+            }
+            rate_limit.calls_in_window += 1;
+
+            let body = match &webhook.body {
+                Some(template) => render_template(template,msg,source),
+                None => json!({
+                    "host": msg.host,
+                    "short_message": msg.short_message,
+                    "level": msg.level,
+                    "facility": msg.facility,
+                    "timestamp": msg.timestamp,
+                    "source": source.to_string(),
+                    "fields": msg.additional_fields
+                }).to_string()
+            };
+
+            match self.sender.try_send(WebhookCall { url: webhook.url.clone(), body }) {
+                Ok(()) => {},
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("the webhook queue is full, not calling {}",webhook.url);
+                    self.state.nr_of_failed_webhook_calls.write().map(|mut x| *x += 1)
+                        .expect("should always be possible to increment failed webhook count");
+                },
+                Err(TrySendError::Disconnected(_)) => log::warn!("the webhook thread is gone, not calling {}",webhook.url)
+            }
+        }
+    }
+}
+
+pub fn spawn(state: Arc<crate::State>, config: Arc<crate::Configuration>) -> WebhookSender {
+    let (sender, receiver) = std::sync::mpsc::sync_channel::<WebhookCall>(WEBHOOK_QUEUE_SIZE);
+    let caller_state = state.clone();
+    std::thread::spawn(move||webhook_caller(caller_state,receiver));
+    let rate_limits = config.webhooks.iter().map(|_|RateLimit { window_start: Instant::now(), calls_in_window: 0 }).collect();
+    WebhookSender { state, config, rate_limits, sender }
+}
+
+fn webhook_caller(state: Arc<crate::State>, receiver: std::sync::mpsc::Receiver<WebhookCall>) {
+
+    let agent = ureq::AgentBuilder::new().timeout(WEBHOOK_TIMEOUT).build();
+
+    while let Ok(call) = receiver.recv() {
+        match agent.post(&call.url).set("Content-Type","application/json").send_string(&call.body) {
+            Ok(_) => {
+                state.nr_of_webhook_calls.write().map(|mut x| *x += 1)
+                    .expect("should always be possible to increment webhook count");
+            },
+            Err(e) => {
+                log::error!("failed to call webhook {}: {e}",call.url);
+                state.nr_of_failed_webhook_calls.write().map(|mut x| *x += 1)
+                    .expect("should always be possible to increment failed webhook count");
+            }
+        }
+    }
+}